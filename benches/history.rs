@@ -0,0 +1,84 @@
+#[macro_use]
+extern crate criterion;
+extern crate bytecache;
+
+use std::hash::Hash;
+use std::hint::black_box;
+use bytecache::history::History;
+use criterion::{ BatchSize, Criterion };
+
+const BUCKET_SIZE: u64 = 16;
+const BUCKET_COUNT: u64 = 256;
+
+const HOT_KEY: u64 = u64::MAX;
+/// Key in the old bucket of `filled_history`.
+const OLD_KEY: u64 = 0;
+
+fn filled_history<K, F>(make_key: F) -> History<K>
+    where
+        K: Eq + Hash + Clone,
+        F: Fn(u64) -> K
+{
+    let mut h = History::new(BUCKET_SIZE, BUCKET_COUNT);
+    for key in 0..BUCKET_SIZE * (BUCKET_COUNT + 1) {
+        h.hit(make_key(key), 1);
+    }
+    h.hit(make_key(HOT_KEY), 1);
+    h
+}
+
+/// Run `f` on the history, spilling old keys after every run so it does not grow.
+fn bench<K, F, R>(c: &mut Criterion, name: &str, mut h: History<K>, mut f: F)
+    where
+        K: Eq + Hash + Clone,
+        F: FnMut(&mut History<K>) -> R
+{
+    let mut spilled = Vec::new();
+
+    c.bench_function(name, |b| b.iter(|| {
+        let res = f(black_box(&mut h));
+        h.spill(&mut spilled);
+        spilled.clear();
+        res
+    }));
+}
+
+fn long_key(key: u64) -> String {
+    format!("https://example.com/some/long/path/to/a/cached/resource/{}", key)
+}
+
+fn advance(c: &mut Criterion) {
+    bench(c, "advance", filled_history(|k| k), |h| h.advance());
+    bench(c, "advance and hit recently aged key", filled_history(|k| k), |h| {
+        h.advance();
+        h.hit(HOT_KEY, 1)
+    });
+}
+
+fn hit(c: &mut Criterion) {
+    bench(c, "hit hot key", filled_history(|k| k), |h| h.hit(HOT_KEY, 1));
+
+    let hot = long_key(HOT_KEY);
+    bench(c, "hit hot string key with clone", filled_history(long_key), |h| h.hit(hot.clone(), 1));
+    bench(c, "hit hot string key by reference", filled_history(long_key), |h| h.hit_ref(&hot, 1));
+
+    let old = filled_history(|k| k);
+    assert!(old.peek_spillable().iter().any(|&(&k, _)| k == OLD_KEY));
+    c.bench_function("hit old key", |b| b.iter_batched(
+        || old.clone(),
+        |mut h| {
+            h.hit(OLD_KEY, 1);
+            h
+        },
+        BatchSize::LargeInput,
+    ));
+
+    let mut missing = HOT_KEY / 2;
+    bench(c, "hit missing key", filled_history(|k| k), |h| {
+        missing += 1;
+        h.hit(missing, 1)
+    });
+}
+
+criterion_group!(benches, advance, hit);
+criterion_main!(benches);
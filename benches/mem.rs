@@ -0,0 +1,40 @@
+#[macro_use]
+extern crate criterion;
+extern crate bytecache;
+
+use std::hint::black_box;
+use bytecache::mem::MemCache;
+use criterion::Criterion;
+
+const VALUE_LEN: usize = 1024;
+
+fn filled_cache() -> MemCache<u64> {
+    let mut cache = MemCache::new(1 << 20);
+    for key in 0..64 {
+        cache.set(key, vec![key as u8; VALUE_LEN]);
+    }
+    cache
+}
+
+fn set(c: &mut Criterion) {
+    let same = vec![7; VALUE_LEN];
+    let other = vec![8; VALUE_LEN];
+
+    let mut cache = filled_cache();
+    let mut i = 0u64;
+    c.bench_function("set identical value", |b| b.iter(|| {
+        i += 1;
+        black_box(&mut cache).set(i % 64, same.clone())
+    }));
+
+    let mut cache = filled_cache();
+    let mut i = 0u64;
+    c.bench_function("set changed value", |b| b.iter(|| {
+        i += 1;
+        let value = if i.is_multiple_of(2) { same.clone() } else { other.clone() };
+        black_box(&mut cache).set(i % 64, value)
+    }));
+}
+
+criterion_group!(benches, set);
+criterion_main!(benches);
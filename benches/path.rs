@@ -0,0 +1,27 @@
+#[macro_use]
+extern crate criterion;
+extern crate bytecache;
+
+use std::hint::black_box;
+use bytecache::path;
+use criterion::Criterion;
+
+const CLEAN: &str = "example.com_some_long_path_to_a_cached_resource_12345";
+const DIRTY: &str = "example.com/some/long/path/to/a/cached/resource/12345";
+
+fn replace_invalid_chars(c: &mut Criterion) {
+    c.bench_function("replace invalid chars in clean key", |b| {
+        b.iter(|| path::replace_invalid_path_chars(black_box(CLEAN)))
+    });
+    c.bench_function("replace invalid chars in key with slashes", |b| {
+        b.iter(|| path::replace_invalid_path_chars(black_box(DIRTY)))
+    });
+}
+
+fn construct(c: &mut Criterion) {
+    c.bench_function("construct clean key", |b| b.iter(|| path::construct(black_box(CLEAN), 2, 2)));
+    c.bench_function("construct key with slashes", |b| b.iter(|| path::construct(black_box(DIRTY), 2, 2)));
+}
+
+criterion_group!(benches, replace_invalid_chars, construct);
+criterion_main!(benches);
@@ -5,7 +5,10 @@ use std::io::prelude::*;
 use bytecache::mem::MemCache;
 
 fn print_state(memcache: &MemCache<String>) {
-    println!("mem usage: {:?}, buckets: {:?}", memcache.usage(), memcache.detailed_usage());
+    let buckets = memcache.detailed_usage_full().iter()
+        .map(|b| (b.bytes, b.items))
+        .collect::<Vec<_>>();
+    println!("mem usage: {:?}, buckets (bytes, items): {:?}", memcache.usage(), buckets);
 }
 
 fn main() {
@@ -20,11 +23,11 @@ fn main() {
     for line in stdin.lock().lines() {
         match line {
             Ok(line) => {
-                if line.starts_with("set") {
-                    let kv = (&line["set".len()..]).trim_left().splitn(2, " ").collect::<Vec<_>>();
+                if let Some(rest) = line.strip_prefix("set") {
+                    let kv = rest.trim_start().splitn(2, ' ').collect::<Vec<_>>();
                     memcache.set(kv[0].to_string(), kv[1].as_bytes().into());
-                } else if line.starts_with("get") {
-                    let key = (&line["get".len()..]).trim_left();
+                } else if let Some(rest) = line.strip_prefix("get") {
+                    let key = rest.trim_start();
                     match memcache.get(key.to_string()) {
                         Some(v) => println!("{}", String::from_utf8_lossy(v)),
                         None => println!("not found"),
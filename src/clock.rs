@@ -0,0 +1,77 @@
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Clock that reads the system time.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when advanced.
+///
+/// Clones share the same time, so a clone can be given to a cache and the
+/// original used to advance it.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Create clock stopped at the current system time.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_advanced() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(start + Duration::from_secs(5), clock.now());
+    }
+
+    #[test]
+    fn manual_clock_clones_share_time() {
+        let clock = ManualClock::new();
+        let other = clock.clone();
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), other.now());
+    }
+}
@@ -1,5 +1,713 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{ Read, Write };
+use std::path::{ Path, PathBuf };
+
+use path::{ self, PathKey };
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use Cache;
+use ReadGuard;
+use CreateReaderError;
+use CreateWriterError;
+
+/// File cache.
+///
+/// Blobs are stored under the root directory, in subdirectories generated by
+/// `path::construct` from the key encoded with `path::encode_component`. Each blob has
+/// a `.meta` sidecar next to it, which records the size requested in `store`. Encoded
+/// keys never contain `.`, so no key can be stored at the path of a sidecar. The sizes recorded in sidecars add up to at most `limit`.
 pub struct FileCache {
-    _limit: u64,
-    _path_subdirs: u32,
-    _path_subdir_len: u32,
+    root: PathBuf,
+    limit: u64,
+    /// Sum of sizes recorded in sidecars, read from disk on first `store`.
+    usage: Option<u64>,
+    path_subdirs: usize,
+    path_subdir_len: usize,
+    dir_mode: Option<u32>,
+    front: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl FileCache {
+    pub fn new(root: PathBuf, limit: u64) -> FileCache {
+        FileCache {
+            root,
+            limit,
+            usage: None,
+            path_subdirs: path::DEF_SUBDIRS,
+            path_subdir_len: path::DEF_SUBDIR_LEN,
+            dir_mode: None,
+            front: HashMap::new(),
+        }
+    }
+
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Create directories with the permission `mode`, for example `0o700`.
+    ///
+    /// Only applies on Unix, and the mode is still masked by the process umask. By default
+    /// directories are created with the OS default permissions.
+    pub fn set_dir_mode(&mut self, mode: u32) {
+        self.dir_mode = Some(mode);
+    }
+
+    pub fn dir_mode(&self) -> Option<u32> {
+        self.dir_mode
+    }
+
+    /// Drop blobs loaded into memory by `prefetch`.
+    pub fn clear_front(&mut self) {
+        self.front.clear();
+    }
+
+    /// Remove blobs without meta sidecars and meta sidecars without blobs.
+    ///
+    /// Such files are left behind by crashes or external changes. Returns the number of
+    /// removed files.
+    pub fn gc(&mut self) -> io::Result<usize> {
+        let mut files = Vec::new();
+        match collect_files(&extended_length(self.root.clone()), &mut files) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        }
+
+        let mut removed = 0;
+        for file in &files {
+            if has_meta(file) || is_meta_of_blob(file) {
+                continue;
+            }
+
+            fs::remove_file(file)?;
+            self.front.remove(file);
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    /// Get a read-only memory map of the blob instead of reading it.
+    ///
+    /// Returns `None` if the blob does not exist or can not be mapped. Prefetched copies
+    /// are not used.
+    ///
+    /// The map is a view of the file, not a copy. Storing the same key again replaces the
+    /// blob with a new file, so live maps keep seeing the old bytes. Do not share the cache
+    /// directory with other writers that change blobs in place: if a mapped file is
+    /// truncated, accessing bytes past its new end crashes the process.
+    #[cfg(feature = "mmap")]
+    pub fn get_mmap<K: PathKey>(&self, key: K) -> Option<Mmap> {
+        let file = fs::File::open(self.blob_path(&key)?).ok()?;
+        // Safety: this cache never writes to existing blobs, `FileWriter` renames a new file
+        // over the blob instead, see the caveats above.
+        unsafe { Mmap::map(&file) }.ok()
+    }
+
+    /// Get the sum of sizes recorded in meta sidecars of existing blobs.
+    fn usage(&mut self) -> io::Result<u64> {
+        if let Some(usage) = self.usage {
+            return Ok(usage);
+        }
+
+        let mut files = Vec::new();
+        match collect_files(&extended_length(self.root.clone()), &mut files) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let usage = files.iter()
+            .filter(|file| is_meta_of_blob(file))
+            .filter_map(|meta| read_meta(meta))
+            .sum();
+        self.usage = Some(usage);
+        Ok(usage)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            if let Some(mode) = self.dir_mode {
+                builder.mode(mode);
+            }
+        }
+
+        builder.create(dir)
+    }
+
+    fn blob_path<K: PathKey>(&self, key: &K) -> Option<PathBuf> {
+        let component = path::encode_component(&key.to_path_component());
+        path::construct(&component, self.path_subdirs, self.path_subdir_len)
+            .map(|p| extended_length(self.root.join(p)))
+    }
+}
+
+/// Use the `\\?\` form for paths that are too long for the Windows `MAX_PATH` limit.
+///
+/// Paths that can not be made absolute or are not valid Unicode are returned unchanged.
+#[cfg(windows)]
+fn extended_length(path: PathBuf) -> PathBuf {
+    // Directories must leave room for an 8.3 file name, so their limit is 248, not 260.
+    const MAX_DIR_PATH: usize = 248;
+
+    let absolute = match ::std::path::absolute(&path) {
+        Ok(absolute) => absolute,
+        Err(_) => return path,
+    };
+    let absolute_str = match absolute.to_str() {
+        Some(s) => s,
+        None => return path,
+    };
+    if absolute_str.len() < MAX_DIR_PATH || absolute_str.starts_with(r"\\?\") {
+        return path;
+    }
+
+    match absolute_str.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", absolute_str)),
+    }
+}
+
+#[cfg(not(windows))]
+fn extended_length(path: PathBuf) -> PathBuf {
+    path
+}
+
+fn has_meta(file: &Path) -> bool {
+    path::meta_path_for(file).is_some_and(|meta| meta.is_file())
+}
+
+fn is_meta_of_blob(file: &Path) -> bool {
+    let name = file.to_string_lossy();
+    match name.strip_suffix(".meta") {
+        Some(blob) => Path::new(blob).is_file(),
+        None => false,
+    }
+}
+
+fn read_meta(meta: &Path) -> Option<u64> {
+    fs::read_to_string(meta).ok()?.trim().parse().ok()
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reader over a blob either loaded into memory or still in a file.
+pub enum FileReader<'a> {
+    Memory(ReadGuard<'a>),
+    File(fs::File),
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            FileReader::Memory(ref mut r) => r.read(buf),
+            FileReader::File(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+impl<K: PathKey> Cache<K> for FileCache {
+    type Reader<'a> = FileReader<'a>;
+    type Writer<'a> = FileWriter<'a>;
+
+    /// Fetch a reader over the blob, served from memory if it was prefetched.
+    fn fetch(&self, key: K) -> Result<FileReader<'_>, CreateReaderError> {
+        let blob_path = match self.blob_path(&key) {
+            Some(p) => p,
+            None => return Err(CreateReaderError::NotFound),
+        };
+
+        if let Some(bytes) = self.front.get(&blob_path) {
+            return Ok(FileReader::Memory(ReadGuard::new(bytes)));
+        }
+
+        match fs::File::open(blob_path) {
+            Ok(f) => Ok(FileReader::File(f)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(CreateReaderError::NotFound),
+            Err(e) => Err(CreateReaderError::Io(e)),
+        }
+    }
+
+    /// Get a writer of the blob, creating its subdirectories.
+    ///
+    /// The blob and its meta sidecar are replaced only on `flush`, see `FileWriter`. Fails
+    /// with `CreateWriterError::OutOfMemory` if the blob would not fit into the limit.
+    /// The size of a replaced blob is not counted.
+    fn store(&mut self, key: K, required_mem: u64) -> Result<FileWriter<'_>, CreateWriterError> {
+        let blob_path = match self.blob_path(&key) {
+            Some(p) => p,
+            None => return Err(CreateWriterError::Io(
+                io::Error::new(io::ErrorKind::InvalidInput, "empty key")
+            )),
+        };
+
+        let meta_path = path::meta_path_for(&blob_path).expect("blob path has a file name");
+        let replaced = if blob_path.is_file() { read_meta(&meta_path).unwrap_or(0) } else { 0 };
+        let usage = self.usage().map_err(CreateWriterError::Io)?.saturating_sub(replaced);
+        if usage.saturating_add(required_mem) > self.limit {
+            return Err(CreateWriterError::OutOfMemory);
+        }
+
+        if let Some(dir) = blob_path.parent() {
+            self.create_dir(dir).map_err(CreateWriterError::Io)?;
+        }
+
+        let tmp_path = path::sidecar_path_for(&blob_path, "tmp").expect("blob path has a file name");
+        let file = fs::File::create(&tmp_path).map_err(CreateWriterError::Io)?;
+
+        Ok(FileWriter {
+            cache: self,
+            file,
+            tmp_path,
+            blob_path,
+            meta_path,
+            required_mem,
+            written: 0,
+            done: false,
+            failed: false,
+        })
+    }
+
+    /// Load blobs of the keys into memory.
+    ///
+    /// Keys that can not be read are skipped.
+    fn prefetch(&mut self, keys: &[K]) {
+        for key in keys {
+            let blob_path = match self.blob_path(key) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            if self.front.contains_key(&blob_path) {
+                continue;
+            }
+
+            if let Ok(bytes) = fs::read(&blob_path) {
+                self.front.insert(blob_path, bytes);
+            }
+        }
+    }
+}
+
+/// Writer of a blob returned by `FileCache::store`.
+///
+/// Bytes are written to a `.tmp` file next to the blob, which replaces the blob on `flush`.
+/// Existing blobs are never written in place, so readers and memory maps of the old blob
+/// are not affected. The meta sidecar and cache usage are updated only after the blob is
+/// replaced.
+///
+/// Writing more than `required_mem` bytes, or any failed write, discards the written
+/// bytes, and `flush` then returns an error. If the writer fails or is dropped without
+/// `flush`, the `.tmp` file is removed and the old blob is kept.
+pub struct FileWriter<'a> {
+    cache: &'a mut FileCache,
+    file: fs::File,
+    tmp_path: PathBuf,
+    blob_path: PathBuf,
+    meta_path: PathBuf,
+    required_mem: u64,
+    written: u64,
+    done: bool,
+    failed: bool,
+}
+
+impl<'a> FileWriter<'a> {
+    fn fail(&mut self) {
+        self.done = true;
+        self.failed = true;
+        let _ = fs::remove_file(&self.tmp_path);
+    }
+
+    /// Rename the `.tmp` file over the blob, returning the cache usage before the rename.
+    fn replace_blob(&mut self) -> io::Result<u64> {
+        self.file.flush()?;
+        let usage = self.cache.usage()?;
+        fs::rename(&self.tmp_path, &self.blob_path)?;
+        Ok(usage)
+    }
+}
+
+impl<'a> Write for FileWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.done {
+            return Err(io::Error::other("blob is already stored or discarded"));
+        }
+        if self.written.saturating_add(buf.len() as u64) > self.required_mem {
+            self.fail();
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "blob is larger than required_mem"));
+        }
+
+        match self.file.write(buf) {
+            Ok(n) => {
+                self.written += n as u64;
+                Ok(n)
+            },
+            Err(e) => {
+                self.fail();
+                Err(e)
+            },
+        }
+    }
+
+    /// Replace the blob with the written bytes, then record its size.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other("blob was discarded"));
+        }
+        if self.done {
+            return Ok(());
+        }
+
+        let replaced = if self.blob_path.is_file() { read_meta(&self.meta_path).unwrap_or(0) } else { 0 };
+        let usage = match self.replace_blob() {
+            Ok(usage) => usage,
+            Err(e) => {
+                self.fail();
+                return Err(e);
+            },
+        };
+
+        self.done = true;
+        self.cache.front.remove(&self.blob_path);
+        if let Err(e) = fs::write(&self.meta_path, self.required_mem.to_string()) {
+            // Recount from disk, the blob is already replaced.
+            self.cache.usage = None;
+            return Err(e);
+        }
+        self.cache.usage = Some(usage.saturating_sub(replaced).saturating_add(self.required_mem));
+
+        Ok(())
+    }
+}
+
+impl<'a> Drop for FileWriter<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{ Read, Write };
+    use std::path::PathBuf;
+    use std::process;
+    use Cache;
+    use CreateReaderError;
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bytecache-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn fetch_all(cache: &FileCache, key: &str) -> Vec<u8> {
+        let mut res = Vec::new();
+        cache.fetch(key).unwrap().read_to_end(&mut res).unwrap();
+        res
+    }
+
+    #[test]
+    fn store_and_fetch() {
+        let dir = test_dir("store_and_fetch");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+
+        cache.store_bytes("test", &[2, 3, 4]).unwrap();
+
+        assert_eq!(vec![2, 3, 4], fetch_all(&cache, "test"));
+        assert!(dir.join("te/st/test").is_file());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_not_fetch_not_stored() {
+        let dir = test_dir("should_not_fetch_not_stored");
+        let cache = FileCache::new(dir, 1000);
+
+        match cache.fetch("test") {
+            Err(CreateReaderError::NotFound) => (),
+            _ => panic!("expected not found"),
+        }
+    }
+
+    #[test]
+    fn prefetched_is_fetched_from_memory() {
+        let dir = test_dir("prefetched_is_fetched_from_memory");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+        cache.store_bytes("test", &[2, 3, 4]).unwrap();
+
+        cache.prefetch(&["test", "missing"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        match cache.fetch("test") {
+            Ok(FileReader::Memory(_)) => (),
+            _ => panic!("expected memory reader"),
+        }
+        assert_eq!(vec![2, 3, 4], fetch_all(&cache, "test"));
+    }
+
+    #[test]
+    fn store_and_fetch_non_ascii_key() {
+        let dir = test_dir("store_and_fetch_non_ascii_key");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+
+        cache.store_bytes("日本", &[1, 2]).unwrap();
+        cache.store_bytes("aé", &[3]).unwrap();
+
+        assert_eq!(vec![1, 2], fetch_all(&cache, "日本"));
+        assert_eq!(vec![3], fetch_all(&cache, "aé"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unflushed_store_keeps_old_blob() {
+        let dir = test_dir("unflushed_store_keeps_old_blob");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+        cache.store_bytes("test", &[1, 2, 3]).unwrap();
+
+        {
+            let mut writer = cache.store("test", 2).unwrap();
+            writer.write_all(&[4, 5]).unwrap();
+        }
+
+        assert_eq!(vec![1, 2, 3], fetch_all(&cache, "test"));
+        assert!(!dir.join("te/st/test.tmp").exists());
+        assert_eq!("3", fs::read_to_string(dir.join("te/st/test.meta")).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_past_required_mem_keeps_old_blob() {
+        let dir = test_dir("write_past_required_mem_keeps_old_blob");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+        cache.store_bytes("test", &[1, 2, 3]).unwrap();
+
+        {
+            let mut writer = cache.store("test", 2).unwrap();
+            writer.write_all(&[4, 5]).unwrap();
+            assert!(writer.write_all(&[6]).is_err());
+            assert!(writer.write(&[6]).is_err());
+            assert!(writer.flush().is_err());
+        }
+
+        assert_eq!(vec![1, 2, 3], fetch_all(&cache, "test"));
+        assert!(!dir.join("te/st/test.tmp").exists());
+        assert_eq!("3", fs::read_to_string(dir.join("te/st/test.meta")).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_store_is_not_counted() {
+        let dir = test_dir("failed_store_is_not_counted");
+        let mut cache = FileCache::new(dir.clone(), 4);
+
+        {
+            let mut writer = cache.store("a", 3).unwrap();
+            assert!(writer.write_all(&[1, 2, 3, 4]).is_err());
+        }
+        {
+            let mut writer = cache.store("b", 4).unwrap();
+            writer.write_all(&[1, 2, 3, 4]).unwrap();
+        }
+        assert!(!dir.join("a").exists());
+        assert!(!dir.join("b").exists());
+
+        cache.store_bytes("c", &[1, 2, 3, 4]).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], fetch_all(&cache, "c"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn store_writes_meta() {
+        let dir = test_dir("store_writes_meta");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+
+        cache.store_bytes("test", &[2, 3, 4]).unwrap();
+
+        assert_eq!("3", fs::read_to_string(dir.join("te/st/test.meta")).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn store_fails_past_limit() {
+        let dir = test_dir("store_fails_past_limit");
+        let mut cache = FileCache::new(dir.clone(), 5);
+        cache.store_bytes("a", &[1, 2, 3]).unwrap();
+
+        match cache.store("b", 3) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        }
+        assert!(!dir.join("b").exists());
+
+        cache.store_bytes("a", &[1, 2, 3, 4, 5]).unwrap();
+
+        let mut reopened = FileCache::new(dir.clone(), 6);
+        reopened.store_bytes("b", &[1]).unwrap();
+        match reopened.store("c", 1) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn key_named_like_sidecar_does_not_collide() {
+        let dir = test_dir("key_named_like_sidecar_does_not_collide");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+
+        cache.store_bytes("abcdef", &[1, 2, 3]).unwrap();
+        cache.store_bytes("abcdef.meta", &[4, 5]).unwrap();
+
+        assert_eq!(vec![1, 2, 3], fetch_all(&cache, "abcdef"));
+        assert_eq!(vec![4, 5], fetch_all(&cache, "abcdef.meta"));
+        assert_eq!("3", fs::read_to_string(dir.join("ab/cd/ef/abcdef.meta")).unwrap());
+        assert_eq!(0, cache.gc().unwrap());
+        assert_eq!(vec![4, 5], fetch_all(&cache, "abcdef.meta"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gc_removes_orphans() {
+        let dir = test_dir("gc_removes_orphans");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+        cache.store_bytes("valid", &[1]).unwrap();
+        cache.store_bytes("x.meta", &[2]).unwrap();
+        cache.store_bytes("blob", &[3]).unwrap();
+        cache.store_bytes("meta", &[4]).unwrap();
+
+        fs::remove_file(dir.join("bl/ob/blob.meta")).unwrap();
+        fs::remove_file(dir.join("me/ta/meta")).unwrap();
+
+        assert_eq!(2, cache.gc().unwrap());
+
+        assert!(!dir.join("bl/ob/blob").exists());
+        assert!(!dir.join("me/ta/meta.meta").exists());
+        assert_eq!(vec![1], fetch_all(&cache, "valid"));
+        assert_eq!(vec![2], fetch_all(&cache, "x.meta"));
+        assert_eq!(0, cache.gc().unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gc_of_missing_root_removes_nothing() {
+        let dir = test_dir("gc_of_missing_root_removes_nothing");
+        let mut cache = FileCache::new(dir, 1000);
+        assert_eq!(0, cache.gc().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn created_dirs_have_requested_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("created_dirs_have_requested_mode");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+        cache.set_dir_mode(0o700);
+
+        cache.store_bytes("test", &[1]).unwrap();
+
+        for sub in &["te", "te/st"] {
+            let mode = fs::metadata(dir.join(sub)).unwrap().permissions().mode();
+            assert_eq!(0o700, mode & 0o777);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn get_mmap_maps_large_blob() {
+        let dir = test_dir("get_mmap_maps_large_blob");
+        let mut cache = FileCache::new(dir.clone(), 1 << 24);
+        let blob = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        cache.store_bytes("large", &blob).unwrap();
+        cache.store_bytes("empty", &[]).unwrap();
+
+        let map = cache.get_mmap("large").unwrap();
+        assert_eq!(blob.len(), map.len());
+        assert!(blob[..] == map[..]);
+        assert!(cache.get_mmap("empty").unwrap().is_empty());
+        assert!(cache.get_mmap("missing").is_none());
+
+        drop(map);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn get_mmap_keeps_old_bytes_after_store() {
+        let dir = test_dir("get_mmap_keeps_old_bytes_after_store");
+        let mut cache = FileCache::new(dir.clone(), 1000);
+        cache.store_bytes("test", &[1; 100]).unwrap();
+
+        let map = cache.get_mmap("test").unwrap();
+        cache.store_bytes("test", &[2; 10]).unwrap();
+
+        assert!(map[..] == [1; 100][..]);
+        assert_eq!(vec![2; 10], fetch_all(&cache, "test"));
+
+        drop(map);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn stores_key_with_very_long_path() {
+        let dir = test_dir("stores_key_with_very_long_path");
+        let root = dir.join("d".repeat(100)).join("e".repeat(100));
+        let mut cache = FileCache::new(root, 1 << 20);
+        let key = "k".repeat(200);
+
+        cache.store_bytes(key.as_str(), &[1, 2, 3]).unwrap();
+        assert_eq!(vec![1, 2, 3], fetch_all(&cache, &key));
+        assert_eq!(0, cache.gc().unwrap());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn extends_only_long_paths() {
+        let short = env::temp_dir().join("short");
+        assert_eq!(short, extended_length(short.clone()));
+
+        let long = env::temp_dir().join("l".repeat(300));
+        assert!(extended_length(long).to_str().unwrap().starts_with(r"\\?\"));
+    }
 }
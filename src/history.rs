@@ -1,10 +1,21 @@
+use std::cmp;
 use std::collections::VecDeque;
 use std::collections::HashMap;
 use std::collections::hash_map;
+use std::collections::TryReserveError;
 use std::hash::Hash;
-use std::borrow::Borrow;
+use std::borrow::{ Borrow, Cow };
+use std::fmt;
 use std::mem;
+use std::sync::Arc;
 
+/// Ceiling of all usage sums.
+///
+/// Sums saturate at this value instead of overflowing. Once a sum is saturated,
+/// removing keys no longer restores the exact value.
+pub const MAX_USAGE: u64 = u64::MAX;
+
+#[derive(Clone)]
 pub struct Bucket<K> {
     items: HashMap<K, u64>,
     usage: u64,
@@ -12,7 +23,7 @@ pub struct Bucket<K> {
 
 /// Bucket tracks the sum of all inserted values.
 ///
-/// When values are replaced, the sum is correctly adjusted.
+/// When values are replaced, the sum is correctly adjusted. The sum saturates at `MAX_USAGE`.
 impl<K> Bucket<K>
     where K: Hash + Eq
 {
@@ -24,48 +35,159 @@ impl<K> Bucket<K>
     }
 
     #[inline(always)]
-    pub fn contains<Q: ?Sized>(&self, key: &Q) -> bool
+    pub fn contains<Q>(&self, key: &Q) -> bool
         where
             K: Borrow<Q>,
-            Q: Hash + Eq
+            Q: ?Sized + Hash + Eq
     {
         self.items.contains_key(key)
     }
 
     #[inline(always)]
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&u64>
+    pub fn get<Q>(&self, key: &Q) -> Option<&u64>
         where
             K: Borrow<Q>,
-            Q: Hash + Eq
+            Q: ?Sized + Hash + Eq
     {
         self.items.get(key)
     }
 
+    /// Get how much the key contributes to `usage()`.
+    pub fn usage_of<Q>(&self, key: &Q) -> Option<u64>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq
+    {
+        self.items.get(key).cloned()
+    }
+
     pub fn insert(&mut self, key: K, required_bytes: u64)
     {
         if let Some(old) = self.items.insert(key, required_bytes) {
-            self.usage -= old;
+            self.usage = self.usage.saturating_sub(old);
         }
 
-        self.usage += required_bytes;
+        self.usage = self.usage.saturating_add(required_bytes);
+    }
+
+    /// Get the value of the key, or insert `f()` and return it if the key is missing.
+    ///
+    /// Existing value is not changed.
+    pub fn get_or_insert_with<F: FnOnce() -> u64>(&mut self, key: K, f: F) -> u64 {
+        match self.items.entry(key) {
+            hash_map::Entry::Occupied(occupied) => *occupied.get(),
+            hash_map::Entry::Vacant(vacant) => {
+                let required_bytes = f();
+                self.usage = self.usage.saturating_add(required_bytes);
+                *vacant.insert(required_bytes)
+            },
+        }
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> bool
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
         where
             K: Borrow<Q>,
-            Q: Hash + Eq
+            Q: ?Sized + Hash + Eq
     {
-        if let Some(value) = self.items.remove(key) {
-            self.usage -= value;
-            return true;
+        self.take(key).is_some()
+    }
+
+    /// Remove the key and return its value.
+    pub fn take<Q>(&mut self, key: &Q) -> Option<u64>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq
+    {
+        self.take_entry(key).map(|(_, value)| value)
+    }
+
+    /// Remove the key and return both the stored key and its value.
+    pub fn take_entry<Q>(&mut self, key: &Q) -> Option<(K, u64)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq
+    {
+        let entry = self.items.remove_entry(key);
+        if let Some((_, value)) = entry {
+            self.usage = self.usage.saturating_sub(value);
         }
-        false
+        entry
+    }
+
+    /// Change the value of existing key, returning the previous value.
+    fn update<Q>(&mut self, key: &Q, required_bytes: u64) -> Option<u64>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq
+    {
+        let value = self.items.get_mut(key)?;
+        let old = mem::replace(value, required_bytes);
+        self.usage = self.usage.saturating_sub(old).saturating_add(required_bytes);
+        Some(old)
+    }
+
+    /// Reserve capacity for at least `additional` more keys.
+    pub fn reserve(&mut self, additional: usize) {
+        self.items.reserve(additional);
+    }
+
+    /// Same as `reserve`, but returns an error instead of aborting if allocation fails.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.items.try_reserve(additional)
+    }
+
+    /// Insert all keys of other bucket.
+    ///
+    /// If both buckets have the same key, the value from other bucket is kept.
+    pub fn merge(&mut self, other: Bucket<K>) {
+        self.extend(other);
     }
 
-    pub fn iter(&self) -> hash_map::Iter<K, u64> {
+    pub fn iter(&self) -> hash_map::Iter<'_, K, u64> {
         self.items.iter()
     }
 
+    /// Remove any one key, returning it with its value.
+    ///
+    /// Each call scans the table from the start, so use `pop_while` or `drain` to remove
+    /// many keys.
+    pub fn pop(&mut self) -> Option<(K, u64)> {
+        let entry = self.items.extract_if(|_, _| true).next();
+        if let Some((_, value)) = entry {
+            self.usage = self.usage.saturating_sub(value);
+        }
+        entry
+    }
+
+    /// Remove keys in no particular order while `more` returns `true` for the bucket usage,
+    /// passing them to `f`.
+    ///
+    /// Unlike repeated `pop`, the table is scanned only once.
+    pub fn pop_while<P, F>(&mut self, mut more: P, mut f: F)
+        where
+            P: FnMut(u64) -> bool,
+            F: FnMut(K, u64)
+    {
+        let mut usage = self.usage;
+        let mut extracted = self.items.extract_if(|_, _| true);
+        while more(usage) {
+            match extracted.next() {
+                Some((key, value)) => {
+                    usage = usage.saturating_sub(value);
+                    f(key, value);
+                },
+                None => break,
+            }
+        }
+        self.usage = usage;
+    }
+
+    /// Remove all keys, returning them with their values.
+    pub fn drain(&mut self) -> hash_map::Drain<'_, K, u64> {
+        self.usage = 0;
+        self.items.drain()
+    }
+
     pub fn clear(&mut self) {
         self.items.clear();
         self.usage = 0;
@@ -74,6 +196,31 @@ impl<K> Bucket<K>
     pub fn usage(&self) -> u64 {
         self.usage
     }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl<K> Default for Bucket<K>
+    where K: Hash + Eq
+{
+    fn default() -> Bucket<K> {
+        Bucket::new()
+    }
+}
+
+impl<K> IntoIterator for Bucket<K> {
+    type Item = (K, u64);
+    type IntoIter = hash_map::IntoIter<K, u64>;
+
+    fn into_iter(self) -> hash_map::IntoIter<K, u64> {
+        self.items.into_iter()
+    }
 }
 
 impl<K> Extend<(K, u64)> for Bucket<K> where K: Eq + Hash {
@@ -88,363 +235,2120 @@ impl<K> Extend<(K, u64)> for Bucket<K> where K: Eq + Hash {
     }
 }
 
+impl<K> PartialEq for Bucket<K> where K: Eq + Hash {
+    fn eq(&self, other: &Bucket<K>) -> bool {
+        self.usage == other.usage && self.items == other.items
+    }
+}
+
+impl<K> Eq for Bucket<K> where K: Eq + Hash {}
+
+/// Prints only the number of keys and usage.
+impl<K> fmt::Debug for Bucket<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bucket")
+            .field("items", &self.items.len())
+            .field("usage", &self.usage)
+            .finish()
+    }
+}
+
+/// Bucket sizing of `History`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BucketConfig {
+    /// Usage after which the current bucket is buried.
+    pub max_bucket_usage: u64,
+    /// Number of buried buckets kept before their keys become old.
+    pub bucket_count: u64,
+}
+
+/// What `History::hit` did with the key.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HitOutcome {
+    /// Key was not tracked before.
+    Inserted,
+    /// Key was already tracked.
+    Refreshed,
+    /// Key was not tracked before, and a bucket was buried, so some keys may have become old.
+    InsertedWithRotation,
+    /// Key was already tracked, and a bucket was buried, so some keys may have become old.
+    RefreshedWithRotation,
+}
+
+impl HitOutcome {
+    fn new(refreshed: bool, rotated: bool) -> HitOutcome {
+        match (refreshed, rotated) {
+            (false, false) => HitOutcome::Inserted,
+            (true, false) => HitOutcome::Refreshed,
+            (false, true) => HitOutcome::InsertedWithRotation,
+            (true, true) => HitOutcome::RefreshedWithRotation,
+        }
+    }
+
+    /// Check if a bucket was buried during the hit.
+    pub fn rotated(&self) -> bool {
+        match *self {
+            HitOutcome::InsertedWithRotation | HitOutcome::RefreshedWithRotation => true,
+            HitOutcome::Inserted | HitOutcome::Refreshed => false,
+        }
+    }
+}
+
+/// Usage of a single bucket.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BucketUsage {
+    /// Sum of bytes of all keys in the bucket.
+    pub bytes: u64,
+    /// Number of keys in the bucket.
+    pub items: usize,
+    /// Usage after which the bucket is buried, `None` for the old bucket.
+    pub max: Option<u64>,
+}
+
+/// How `History` decides which keys become old.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// Every key ages through the same buckets.
+    Recency,
+    /// Segmented LRU.
+    ///
+    /// Keys hit at least twice are moved to a protected segment with its own buckets.
+    /// Keys aging out of the protected segment are demoted back to the probationary
+    /// buckets, so they become old only after the probationary keys did.
+    Segmented,
+}
+
+/// Whether hits with unchanged size move the key to the current bucket.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RefreshPolicy {
+    /// Every hit moves the key to the current bucket.
+    Always,
+    /// Hits move the key only if its size changed, otherwise the key stays where it is.
+    OnlyIfChanged,
+}
+
+#[derive(Clone)]
 pub struct History<K> {
     max_bucket_usage: u64,
     bucket_count: u64,
     next_bucket: Bucket<K>,
     old_bucket: Bucket<K>,
     buckets: VecDeque<Bucket<K>>,
+    usage: u64,
+    rotations: u64,
+    refresh_policy: RefreshPolicy,
+    protected: Option<Box<History<K>>>,
+    old_bucket_cap: Option<u64>,
+    on_old_drop: Option<Arc<dyn Fn(K, u64) + Send + Sync>>,
+    /// Old keys not yet removed by `spill_one`. May contain keys that are no longer old.
+    spill_queue: Vec<K>,
 }
 
 impl<K> History<K>
     where K: Eq + Hash + Clone
 {
+    /// Create history of `bucket_count` buried buckets of `max_bucket_usage` bytes.
+    ///
+    /// With `bucket_count` of 0, only the current bucket is kept, and keys become old
+    /// as soon as it fills up.
     pub fn new(max_bucket_usage: u64, bucket_count: u64) -> History<K> {
+        History::with_config(BucketConfig {
+            max_bucket_usage,
+            bucket_count,
+        })
+    }
+
+    pub fn with_config(config: BucketConfig) -> History<K> {
         History::<K> {
-            max_bucket_usage: max_bucket_usage,
-            bucket_count: bucket_count,
+            max_bucket_usage: config.max_bucket_usage,
+            bucket_count: config.bucket_count,
             next_bucket: Bucket::new(),
             old_bucket: Bucket::new(),
             buckets: VecDeque::new(),
+            usage: 0,
+            rotations: 0,
+            refresh_policy: RefreshPolicy::Always,
+            protected: None,
+            old_bucket_cap: None,
+            on_old_drop: None,
+            spill_queue: Vec::new(),
         }
     }
 
-    /// Refresh the key to delay its removal or to insert it to history.
-    pub fn hit(&mut self, key: K, required_bytes: u64)
-        where
-            K: Eq + Hash
-    {
-        let next_bucket_usage = self.next_bucket.usage();
-        let remaining_space = if self.max_bucket_usage > next_bucket_usage {
-            self.max_bucket_usage - next_bucket_usage
-        } else {
-            0
-        };
-
-        if required_bytes > remaining_space {
-            self.burry_bucket();
+    pub fn with_eviction_policy(max_bucket_usage: u64, bucket_count: u64, policy: EvictionPolicy) -> History<K> {
+        let mut history = History::new(max_bucket_usage, bucket_count);
+        if let EvictionPolicy::Segmented = policy {
+            history.protected = Some(Box::new(History::new(max_bucket_usage, bucket_count)));
         }
+        history
+    }
 
-        self.insert(key, required_bytes);
+    pub fn config(&self) -> BucketConfig {
+        BucketConfig {
+            max_bucket_usage: self.max_bucket_usage,
+            bucket_count: self.bucket_count,
+        }
     }
 
-    fn insert(&mut self, key: K, required_bytes: u64)
-        where
-            K: Eq + Hash
-    {
-        let maybe_bytes = self.next_bucket.get(&key).map(|v| *v);
+    /// Limit usage of the old bucket.
+    ///
+    /// When old keys would exceed the cap, keys that were old the longest are dropped
+    /// without waiting for `spill`, and passed to the callback set with `on_old_drop`.
+    /// Use it when history is used without spilling. Does not apply to the protected
+    /// segment, which is emptied into probation anyway.
+    pub fn set_old_bucket_cap(&mut self, cap: Option<u64>) {
+        self.old_bucket_cap = cap;
+        self.drop_old(0);
+    }
 
-        if let Some(bytes) = maybe_bytes {
-            if bytes != required_bytes {
-                self.next_bucket.insert(key, required_bytes);
-            }
+    pub fn old_bucket_cap(&self) -> Option<u64> {
+        self.old_bucket_cap
+    }
 
-            return;
-        }
+    /// Call `f` with every key dropped because of the old bucket cap.
+    pub fn on_old_drop<F: Fn(K, u64) + Send + Sync + 'static>(&mut self, f: F) {
+        self.on_old_drop = Some(Arc::new(f));
+    }
 
-        self.old_bucket.remove(&key);
-        self.dig_out(&key);
+    /// Drop old keys until `incoming` more bytes fit under the old bucket cap.
+    fn drop_old(&mut self, incoming: u64) {
+        let cap = match self.old_bucket_cap {
+            Some(cap) => cap,
+            None => return,
+        };
 
-        self.next_bucket.insert(key, required_bytes);
+        let before = self.old_bucket.usage();
+        let on_old_drop = &self.on_old_drop;
+        self.old_bucket.pop_while(|usage| usage.saturating_add(incoming) > cap, |key, bytes| {
+            if let Some(ref f) = *on_old_drop {
+                f(key, bytes);
+            }
+        });
+        self.usage = self.usage.saturating_sub(before - self.old_bucket.usage());
     }
 
-    /// Remove key from history.
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> bool
-        where
-            K: Borrow<Q>,
-            Q: Eq + Hash
-    {
-        if self.next_bucket.remove(key) {
-            return true;
-        }
+    /// Get total byte capacity of the buried buckets and the current bucket.
+    ///
+    /// The old bucket is not counted, because it is not bounded: it holds whatever
+    /// was not spilled yet. The protected segment of `EvictionPolicy::Segmented` is not
+    /// counted either.
+    pub fn capacity(&self) -> u64 {
+        self.max_bucket_usage.saturating_mul(self.bucket_count.saturating_add(1))
+    }
 
-        if self.dig_out(key) {
-            return true;
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        match self.protected {
+            Some(_) => EvictionPolicy::Segmented,
+            None => EvictionPolicy::Recency,
         }
+    }
 
-        self.old_bucket.remove(key)
+    pub fn refresh_policy(&self) -> RefreshPolicy {
+        self.refresh_policy
     }
 
-    /// Remove all elements that are "old".
+    /// Set the refresh policy, also used by the protected segment.
     ///
-    /// Old elements no longer fit into defined buckets.
-    pub fn spill<E: Extend<(K, u64)>>(&mut self, target: &mut E) {
-        target.extend(self.old_bucket.iter().map(
-            |(k, v)| (k.clone(), *v)
-        ));
-        self.old_bucket.clear();
+    /// With `EvictionPolicy::Segmented`, probationary keys are still promoted on every hit,
+    /// `RefreshPolicy::OnlyIfChanged` only keeps keys in place within a segment.
+    pub fn set_refresh_policy(&mut self, policy: RefreshPolicy) {
+        self.refresh_policy = policy;
+        if let Some(ref mut protected) = self.protected {
+            protected.set_refresh_policy(policy);
+        }
     }
 
-    pub fn clear(&mut self) {
-        self.next_bucket.clear();
-        self.old_bucket.clear();
-        self.buckets.clear();
+    /// Refresh the key to delay its removal or to insert it to history.
+    pub fn hit(&mut self, key: K, required_bytes: u64) -> HitOutcome {
+        self.hit_with_outcome(Cow::Owned(key), required_bytes)
     }
 
-    /// Get usage of all buckets.
-    pub fn detailed_usage(&self) -> Vec<(u64, Option<u64>)> {
-        let mut res = Vec::with_capacity(2 + self.bucket_count as usize);
+    /// Same as `hit`, but clones the key only if it is not tracked yet.
+    pub fn hit_ref(&mut self, key: &K, required_bytes: u64) -> HitOutcome {
+        self.hit_with_outcome(Cow::Borrowed(key), required_bytes)
+    }
 
-        res.push((self.old_bucket.usage(), None));
-        for b in &self.buckets {
-            res.push((b.usage(), Some(self.max_bucket_usage)));
+    /// Hit all keys in order.
+    ///
+    /// Ends in the same state as calling `hit` for each key, but buckets are rotated once
+    /// for the whole batch: the final bucket of every key is found first, then buckets are
+    /// rotated at most `bucket_count + 1` times and every key is moved straight to its
+    /// final bucket. With an old bucket cap, different old keys may be dropped than with
+    /// single hits. With `EvictionPolicy::Segmented`, keys are hit one by one.
+    pub fn hit_many<I: IntoIterator<Item = (K, u64)>>(&mut self, items: I) {
+        if self.protected.is_some() {
+            for (key, required_bytes) in items {
+                self.hit_cow(Cow::Owned(key), required_bytes);
+            }
+            return;
         }
-        res.push((self.next_bucket.usage(), Some(self.max_bucket_usage)));
 
-        res
-    }
+        // Generation of the bucket each key ends in, 0 is the current next bucket.
+        let mut placed: HashMap<K, (u64, u64)> = HashMap::new();
+        let mut generation = 0;
+        let mut next_usage = self.next_bucket.usage();
+
+        for (key, required_bytes) in items {
+            if self.refresh_policy == RefreshPolicy::OnlyIfChanged {
+                let tracked = match placed.get(&key) {
+                    Some(&(_, bytes)) => Some(bytes),
+                    None => self.usage_of(&key),
+                };
+                if tracked == Some(required_bytes) {
+                    continue;
+                }
+            }
 
-    /// Get usage of all buckets.
-    pub fn simple_usage(&self) -> Vec<u64> {
-        let mut res = Vec::with_capacity(2 + self.bucket_count as usize);
+            if required_bytes > self.max_bucket_usage.saturating_sub(next_usage) {
+                generation += 1;
+                next_usage = 0;
+            }
 
-        res.push(self.old_bucket.usage());
-        for b in &self.buckets {
-            res.push(b.usage());
+            let in_next = match placed.get(&key) {
+                Some(&(g, bytes)) if g == generation => Some(bytes),
+                Some(_) => None,
+                None if generation == 0 => self.next_bucket.usage_of(&key),
+                None => None,
+            };
+            next_usage = next_usage.saturating_sub(in_next.unwrap_or(0)).saturating_add(required_bytes);
+            placed.insert(key, (generation, required_bytes));
         }
-        res.push(self.next_bucket.usage());
-
-        res
-    }
 
-    /// Get total usage.
-    pub fn usage(&self) -> u64 {
-        let mut res = self.old_bucket.usage();
-        for b in &self.buckets {
-            res += b.usage();
+        for key in placed.keys() {
+            if let Some((_, bytes)) = self.take(key) {
+                self.usage = self.usage.saturating_sub(bytes);
+            }
         }
-        res += self.next_bucket.usage();
 
-        res
+        // After `bucket_count + 1` rotations all previous buckets are old, more rotations
+        // only bury empty buckets.
+        let rotated = cmp::min(generation, self.bucket_count.saturating_add(1));
+        for _ in 0..rotated {
+            self.burry_bucket();
+        }
+        self.rotations += generation - rotated;
+
+        let buried = self.buckets.len() as u64;
+        for (key, (g, required_bytes)) in placed {
+            let age = generation - g;
+            let bucket = if age == 0 {
+                &mut self.next_bucket
+            } else if age <= buried {
+                &mut self.buckets[(buried - age) as usize]
+            } else {
+                &mut self.old_bucket
+            };
+            bucket.insert(key, required_bytes);
+            self.usage = self.usage.saturating_add(required_bytes);
+        }
+        self.drop_old(0);
     }
 
-    /// Find the key in bucket history and remove it from there.
-    fn dig_out<Q: ?Sized>(&mut self, key: &Q) -> bool
-        where
-            K: Borrow<Q>,
-            Q: Eq + Hash
-    {
-        for b in &mut self.buckets {
-            if b.remove(key) {
-                return true;
-            }
-        }
-        false
+    fn hit_with_outcome(&mut self, key: Cow<'_, K>, required_bytes: u64) -> HitOutcome {
+        let rotations = self.rotations;
+        let refreshed = self.hit_cow(key, required_bytes);
+        HitOutcome::new(refreshed, self.rotations != rotations)
     }
 
-    fn burry_bucket(&mut self) {
-        let new_bucket = if self.buckets.len() as u64 >= self.bucket_count {
-            let mut old = self.buckets.pop_front().unwrap();
-            self.old_bucket.extend(old.iter().map(
-                |(k, v)| (k.clone(), *v)
-            ));
-            old.clear();
-            old
+    /// Returns `true` if the key was already tracked.
+    fn hit_cow(&mut self, key: Cow<'_, K>, required_bytes: u64) -> bool {
+        // Protected keys are refreshed by the protected segment, which has the same policy,
+        // and probationary keys are promoted regardless of the policy.
+        let protected_hit = match self.protected {
+            Some(ref protected) => protected.contains(&*key),
+            None => {
+                if self.refresh_policy == RefreshPolicy::OnlyIfChanged
+                    && self.usage_of(&*key) == Some(required_bytes) {
+                    return true;
+                }
+                return self.hit_probation(key, required_bytes);
+            },
+        };
+
+        let key = if protected_hit {
+            key
         } else {
-            Bucket::new()
+            match self.take(&*key) {
+                Some((owned, bytes)) => {
+                    self.usage = self.usage.saturating_sub(bytes);
+                    Cow::Owned(owned)
+                },
+                None => return self.hit_probation(key, required_bytes),
+            }
         };
 
-        let mut current_bucket = new_bucket;
-        mem::swap(&mut current_bucket, &mut self.next_bucket);
+        if let Some(ref mut protected) = self.protected {
+            protected.hit_cow(key, required_bytes);
+        }
+        self.demote_protected();
 
-        self.buckets.push_back(current_bucket);
+        true
     }
-}
-
-#[cfg(test)]
-mod bucket_test {
-    use super::*;
 
-    #[test]
-    fn usage_is_zero() {
-        let b = Bucket::<u32>::new();
-        assert_eq!(0, b.usage());
+    /// Check if the key is tracked by this history.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash
+    {
+        self.usage_of(key).is_some()
     }
 
-    #[test]
-    fn store_correct_usage() {
-        let mut b = Bucket::new();
-        b.insert(3, 2);
-        assert_eq!(2, b.usage());
-        b.insert(2, 3);
-        assert_eq!(5, b.usage());
+    /// Get how much the key contributes to `usage()`.
+    pub fn usage_of<Q>(&self, key: &Q) -> Option<u64>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash
+    {
+        self.next_bucket.usage_of(key)
+            .or_else(|| self.buckets.iter().rev().filter_map(|b| b.usage_of(key)).next())
+            .or_else(|| self.old_bucket.usage_of(key))
+            .or_else(|| self.protected.as_ref().and_then(|p| p.usage_of(key)))
     }
 
-    #[test]
-    fn contains_stored() {
-        let mut b = Bucket::new();
-        b.insert(3, 2);
-        assert!(b.contains(&3));
-    }
+    fn hit_probation(&mut self, key: Cow<'_, K>, required_bytes: u64) -> bool {
+        let next_bucket_usage = self.next_bucket.usage();
+        let remaining_space = self.max_bucket_usage.saturating_sub(next_bucket_usage);
 
-    #[test]
-    fn not_contains_removed() {
-        let mut b = Bucket::new();
-        b.insert(3, 2);
-        b.remove(&3);
-        assert!(!b.contains(&3));
-        assert_eq!(0, b.usage());
+        if required_bytes > remaining_space {
+            self.burry_bucket();
+        }
+
+        self.insert(key, required_bytes)
     }
 
-    #[test]
-    fn is_iteratable() {
-        let mut b = Bucket::new();
-        b.insert(3, 2);
-        b.insert(1, 1);
+    /// Age the history by one step without inserting anything.
+    ///
+    /// Unconditionally buries the current bucket, so that recency decays with time
+    /// and not only with the volume of hits.
+    pub fn advance(&mut self) {
+        self.burry_bucket();
 
-        let all = b.iter().map(|(k, _)| *k).collect::<Vec<_>>();
-        assert!(all.contains(&3));
-        assert!(all.contains(&1));
+        if let Some(ref mut protected) = self.protected {
+            protected.advance();
+        }
+        self.demote_protected();
     }
 
-    #[test]
-    fn not_contains_cleared() {
-        let mut b = Bucket::new();
-        b.insert(3, 2);
-        b.clear();
-        assert!(!b.contains(&3));
-        assert_eq!(0, b.usage());
+    /// Move keys that aged out of the protected segment to the probationary buckets.
+    fn demote_protected(&mut self) {
+        let mut demoted = Vec::new();
+        if let Some(ref mut protected) = self.protected {
+            protected.spill(&mut demoted);
+        }
+
+        for (key, required_bytes) in demoted {
+            self.hit_probation(Cow::Owned(key), required_bytes);
+        }
     }
 
-    #[test]
-    fn can_be_extended() {
-        let mut b = Bucket::new();
-        b.insert(3, 2);
+    /// Move the key to the next bucket.
+    ///
+    /// Borrowed key is cloned only if it is not found in any bucket. Returns `true`
+    /// if the key was found.
+    fn insert(&mut self, key: Cow<'_, K>, required_bytes: u64) -> bool {
+        if let Some(bytes) = self.next_bucket.update(&*key, required_bytes) {
+            self.usage = self.usage.saturating_sub(bytes).saturating_add(required_bytes);
+            return true;
+        }
 
-        let mut c = Bucket::new();
-        c.insert(1, 1);
+        // The old bucket is checked before buried buckets, because it is a single lookup, and
+        // keys about to be evicted are often hit again.
+        let old_entry = self.old_bucket.take_entry(&*key)
+            .or_else(|| self.dig_out(&*key));
+        let found = old_entry.is_some();
+        let key = match old_entry {
+            Some((owned, bytes)) => {
+                self.usage = self.usage.saturating_sub(bytes);
+                owned
+            },
+            None => key.into_owned(),
+        };
 
-        b.extend(c.iter().map(|(k, v)| (*k, *v)));
+        self.next_bucket.insert(key, required_bytes);
+        self.usage = self.usage.saturating_add(required_bytes);
 
-        assert!(b.contains(&3));
-        assert!(b.contains(&1));
-        assert_eq!(3, b.usage());
+        found
+    }
 
-        assert!(c.contains(&1));
-        assert_eq!(1, c.usage());
+    /// Reserve capacity for at least `additional` more keys in the current bucket.
+    pub fn reserve(&mut self, additional: usize) {
+        self.next_bucket.reserve(additional);
     }
-}
 
-#[cfg(test)]
-mod history_test {
-    use std::collections::BTreeSet;
-    use std::hash::Hash;
+    /// Same as `reserve`, but returns an error instead of aborting if allocation fails.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.next_bucket.try_reserve(additional)
+    }
+
+    /// Hit every key from other history, oldest first.
+    ///
+    /// Relative recency of keys from other history is preserved, but bucket boundaries
+    /// are not: keys are redistributed according to this history's bucket sizes.
+    pub fn merge_from(&mut self, other: History<K>) {
+        let buckets = Some(other.old_bucket).into_iter()
+            .chain(other.buckets)
+            .chain(Some(other.next_bucket));
+
+        for bucket in buckets {
+            for (key, required_bytes) in bucket {
+                self.hit(key, required_bytes);
+            }
+        }
+
+        if let Some(protected) = other.protected {
+            self.merge_from(*protected);
+        }
+    }
+
+    /// Remove key from history.
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash
+    {
+        self.remove_returning_size(key).is_some()
+    }
+
+    /// Remove key from history and return the bytes it was tracked with.
+    pub fn remove_returning_size<Q>(&mut self, key: &Q) -> Option<u64>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash
+    {
+        match self.take(key) {
+            Some((_, bytes)) => {
+                self.usage = self.usage.saturating_sub(bytes);
+                Some(bytes)
+            },
+            None => match self.protected {
+                Some(ref mut protected) => protected.remove_returning_size(key),
+                None => None,
+            },
+        }
+    }
+
+    /// Remove key from probationary buckets, without adjusting usage.
+    fn take<Q>(&mut self, key: &Q) -> Option<(K, u64)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash
+    {
+        self.next_bucket.take_entry(key)
+            .or_else(|| self.old_bucket.take_entry(key))
+            .or_else(|| self.dig_out(key))
+    }
+
+    /// Merge sparse buried buckets and drop empty ones.
+    ///
+    /// Neighbouring buckets are merged into the older one while their usage fits into a
+    /// single bucket, so recency order is preserved. This speeds up hits of aged keys,
+    /// but may slightly alter eviction boundaries: merged keys age together, and keys
+    /// become old later because there are fewer buried buckets.
+    pub fn compact(&mut self) {
+        let mut compacted: VecDeque<Bucket<K>> = VecDeque::with_capacity(self.buckets.len());
+
+        for b in self.buckets.drain(..) {
+            if b.is_empty() {
+                continue;
+            }
+            if let Some(last) = compacted.back_mut() {
+                if last.usage().saturating_add(b.usage()) <= self.max_bucket_usage {
+                    last.merge(b);
+                    continue;
+                }
+            }
+            compacted.push_back(b);
+        }
+
+        self.buckets = compacted;
+
+        if let Some(ref mut protected) = self.protected {
+            protected.compact();
+        }
+    }
+
+    /// Remove all elements that are "old".
+    ///
+    /// Old elements no longer fit into defined buckets. With `EvictionPolicy::Segmented`,
+    /// protected elements must be demoted first before they can become old.
+    pub fn spill<E: Extend<(K, u64)>>(&mut self, target: &mut E) {
+        self.usage = self.usage.saturating_sub(self.old_bucket.usage());
+        self.spill_queue.clear();
+        target.extend(self.old_bucket.drain());
+    }
+
+    /// Remove all elements that are "old", except the given key.
+    pub fn spill_except<Q, E>(&mut self, key: &Q, target: &mut E)
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+            E: Extend<(K, u64)>
+    {
+        let kept = self.old_bucket.take_entry(key);
+        self.spill(target);
+
+        if let Some((key, bytes)) = kept {
+            self.old_bucket.insert(key, bytes);
+        }
+    }
+
+    /// Remove elements one by one, oldest first, until total usage is at most `target_total`.
+    ///
+    /// Old elements are removed first, then elements of buried buckets from the oldest one,
+    /// then of the current bucket, and then of the protected segment. Buckets are not
+    /// rotated, so remaining elements keep their recency. Stops as soon as usage is at or
+    /// below the target, so usage ends just below it.
+    pub fn spill_to<F: FnMut(K, u64)>(&mut self, target_total: u64, mut f: F) {
+        self.spill_to_keeping::<K, F>(target_total, None, &mut f);
+    }
+
+    /// Same as `spill_to`, but never removes `key`.
+    pub fn spill_to_except<Q, F>(&mut self, target_total: u64, key: &Q, mut f: F)
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+            F: FnMut(K, u64)
+    {
+        self.spill_to_keeping(target_total, Some(key), &mut f);
+    }
+
+    fn spill_to_keeping<Q, F>(&mut self, target_total: u64, keep: Option<&Q>, f: &mut F)
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Hash + Eq,
+            F: FnMut(K, u64)
+    {
+        let mut usage = self.usage();
+        let mut freed = 0;
+        let buckets = Some(&mut self.old_bucket).into_iter()
+            .chain(self.buckets.iter_mut())
+            .chain(Some(&mut self.next_bucket));
+
+        for bucket in buckets {
+            if usage <= target_total {
+                break;
+            }
+
+            let kept = keep.and_then(|key| bucket.take_entry(key));
+            let excess = usage - target_total;
+            let before = bucket.usage();
+            bucket.pop_while(|left| before - left < excess, &mut *f);
+            usage = usage.saturating_sub(before - bucket.usage());
+            freed += before - bucket.usage();
+
+            if let Some((key, bytes)) = kept {
+                bucket.insert(key, bytes);
+            }
+        }
+        self.usage = self.usage.saturating_sub(freed);
+        if usage <= target_total {
+            return;
+        }
+
+        if let Some(ref mut protected) = self.protected {
+            let target = target_total.saturating_sub(self.usage);
+            protected.spill_to_keeping(target, keep, f);
+        }
+    }
+
+    /// Get bytes that can be freed with the least cost to the hit ratio.
+    ///
+    /// This is the usage of the old bucket plus the oldest buried bucket, which is the
+    /// next to become old. Newer buckets and the protected segment are not counted.
+    pub fn reclaimable_bytes(&self) -> u64 {
+        let next_old = self.buckets.front().map_or(0, |b| b.usage());
+        self.old_bucket.usage().saturating_add(next_old)
+    }
+
+    /// Get "old" elements that `spill` would remove, without removing them.
+    pub fn peek_spillable(&self) -> Vec<(&K, u64)> {
+        self.old_bucket.iter().map(|(k, &bytes)| (k, bytes)).collect()
+    }
+
+    /// Remove a single "old" element, in no particular order.
+    ///
+    /// Returns `None` if there are no old elements. Old keys are listed once and then
+    /// removed one per call, so spilling all of them one by one takes linear time.
+    pub fn spill_one(&mut self) -> Option<(K, u64)> {
+        loop {
+            let key = match self.spill_queue.pop() {
+                Some(key) => key,
+                None if self.old_bucket.is_empty() => return None,
+                None => {
+                    self.spill_queue.extend(self.old_bucket.iter().map(|(key, _)| key.clone()));
+                    continue;
+                },
+            };
+
+            if let Some((key, bytes)) = self.old_bucket.take_entry(&key) {
+                self.usage = self.usage.saturating_sub(bytes);
+                return Some((key, bytes));
+            }
+        }
+    }
+
+    /// Remove all keys.
+    ///
+    /// Configuration, policies and the rotation count are preserved.
+    pub fn clear(&mut self) {
+        self.next_bucket.clear();
+        self.old_bucket.clear();
+        self.spill_queue.clear();
+        self.buckets.clear();
+        self.usage = 0;
+
+        if let Some(ref mut protected) = self.protected {
+            protected.clear();
+        }
+    }
+
+    /// Remove all keys, apply new bucket configuration and reset the rotation count.
+    ///
+    /// Eviction and refresh policies are preserved. The protected segment gets the same
+    /// configuration.
+    pub fn reset(&mut self, config: BucketConfig) {
+        self.clear();
+        self.max_bucket_usage = config.max_bucket_usage;
+        self.bucket_count = config.bucket_count;
+        self.rotations = 0;
+
+        if let Some(ref mut protected) = self.protected {
+            protected.reset(config);
+        }
+    }
+
+    /// Get usage of all buckets.
+    pub fn detailed_usage(&self) -> Vec<(u64, Option<u64>)> {
+        self.detailed_usage_iter().collect()
+    }
+
+    /// Iterate usage of all buckets without allocating, in the same order as `detailed_usage`.
+    pub fn detailed_usage_iter(&self) -> impl Iterator<Item = (u64, Option<u64>)> + '_ {
+        let max = self.max_bucket_usage;
+        self.buckets_oldest_first()
+            .enumerate()
+            .map(move |(i, b)| (b.usage(), if i == 0 { None } else { Some(max) }))
+    }
+
+    /// Probationary buckets from the old bucket to the next bucket.
+    fn buckets_oldest_first(&self) -> impl Iterator<Item = &Bucket<K>> + '_ {
+        Some(&self.old_bucket).into_iter()
+            .chain(self.buckets.iter())
+            .chain(Some(&self.next_bucket))
+    }
+
+    /// Iterate keys from the most recent bucket to the old bucket.
+    ///
+    /// Yields the key, its bytes and the index of its bucket, which is 0 for the current
+    /// bucket. With `EvictionPolicy::Segmented`, protected buckets come first. Order of keys
+    /// in the same bucket is arbitrary.
+    pub fn iter_by_recency(&self) -> impl Iterator<Item = (&K, u64, usize)> + '_ {
+        self.buckets_by_recency().into_iter()
+            .enumerate()
+            .flat_map(|(i, b)| b.iter().map(move |(k, &bytes)| (k, bytes, i)))
+    }
+
+    /// Get all keys with their bytes from the oldest to the most recent.
+    ///
+    /// Replaying them with `import_order` restores the approximate recency order, for
+    /// example after a restart. Order of keys in the same bucket is arbitrary.
+    pub fn export_order(&self) -> Vec<(K, u64)> {
+        let mut res = self.iter_by_recency()
+            .map(|(k, bytes, _)| (k.clone(), bytes))
+            .collect::<Vec<_>>();
+        res.reverse();
+        res
+    }
+
+    /// Hit all keys in order, see `export_order`.
+    pub fn import_order(&mut self, entries: Vec<(K, u64)>) {
+        self.hit_many(entries);
+    }
+
+    fn buckets_by_recency(&self) -> Vec<&Bucket<K>> {
+        let mut res = match self.protected {
+            Some(ref protected) => protected.buckets_by_recency(),
+            None => Vec::with_capacity(2 + self.bucket_count as usize),
+        };
+
+        res.push(&self.next_bucket);
+        res.extend(self.buckets.iter().rev());
+        res.push(&self.old_bucket);
+
+        res
+    }
+
+    /// Get any key from the most recent non-empty bucket.
+    ///
+    /// Keys in the same bucket have no strict order, so this is one of the most recently
+    /// hit keys, not necessarily the last one. With `EvictionPolicy::Segmented`, protected
+    /// keys are hotter than probationary ones.
+    pub fn hottest(&self) -> Option<&K> {
+        if let Some(key) = self.protected.as_ref().and_then(|p| p.hottest()) {
+            return Some(key);
+        }
+
+        Some(&self.next_bucket).into_iter()
+            .chain(self.buckets.iter().rev())
+            .chain(Some(&self.old_bucket))
+            .find_map(|b| b.iter().next().map(|(k, _)| k))
+    }
+
+    /// Get any key from the oldest non-empty bucket.
+    ///
+    /// Keys in the same bucket have no strict order, so this is one of the keys that
+    /// would be spilled first, not necessarily the least recently hit one.
+    pub fn coldest(&self) -> Option<&K> {
+        let probation = Some(&self.old_bucket).into_iter()
+            .chain(self.buckets.iter())
+            .chain(Some(&self.next_bucket))
+            .find_map(|b| b.iter().next().map(|(k, _)| k));
+
+        probation.or_else(|| self.protected.as_ref().and_then(|p| p.coldest()))
+    }
+
+    /// Get usage and item count of all buckets.
+    ///
+    /// Buckets are in the same order as in `detailed_usage`, oldest first.
+    pub fn detailed_usage_full(&self) -> Vec<BucketUsage> {
+        let max = self.max_bucket_usage;
+        self.buckets_oldest_first()
+            .enumerate()
+            .map(|(i, b)| BucketUsage {
+                bytes: b.usage(),
+                items: b.len(),
+                max: if i == 0 { None } else { Some(max) },
+            })
+            .collect()
+    }
+
+    /// Get usage of all buckets.
+    pub fn simple_usage(&self) -> Vec<u64> {
+        self.simple_usage_iter().collect()
+    }
+
+    /// Iterate usage of all buckets without allocating, in the same order as `simple_usage`.
+    pub fn simple_usage_iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.buckets_oldest_first().map(|b| b.usage())
+    }
+
+    /// Get total usage.
+    ///
+    /// The total is maintained incrementally, so this is O(1).
+    pub fn usage(&self) -> u64 {
+        self.usage.saturating_add(self.protected_usage())
+    }
+
+    /// Get number of tracked keys in all buckets.
+    pub fn total_items(&self) -> usize {
+        let protected = match self.protected {
+            Some(ref protected) => protected.total_items(),
+            None => 0,
+        };
+
+        self.old_bucket.len()
+            + self.buckets.iter().map(|b| b.len()).sum::<usize>()
+            + self.next_bucket.len()
+            + protected
+    }
+
+    /// Get how many times buckets were rotated.
+    ///
+    /// High rotation count relative to hits means the buckets are too small.
+    pub fn rotation_count(&self) -> u64 {
+        self.rotations
+    }
+
+    /// Get usage of the protected segment.
+    ///
+    /// It is not included in `simple_usage` and `detailed_usage`, which only report
+    /// probationary buckets.
+    pub fn protected_usage(&self) -> u64 {
+        match self.protected {
+            Some(ref protected) => protected.usage(),
+            None => 0,
+        }
+    }
+
+    /// Find the key in bucket history and remove it from there.
+    ///
+    /// Buckets are searched from the most recent one, because keys that are hit again
+    /// are most likely to have been buried recently.
+    fn dig_out<Q>(&mut self, key: &Q) -> Option<(K, u64)>
+        where
+            K: Borrow<Q>,
+            Q: ?Sized + Eq + Hash
+    {
+        for b in self.buckets.iter_mut().rev() {
+            if let Some(entry) = b.take_entry(key) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    fn burry_bucket(&mut self) {
+        // Without buried buckets, the current bucket becomes old right away.
+        if self.bucket_count == 0 {
+            self.drop_old(self.next_bucket.usage());
+            self.old_bucket.extend(self.next_bucket.drain());
+            self.drop_old(0);
+            self.rotations += 1;
+            return;
+        }
+
+        let new_bucket = if self.buckets.len() as u64 >= self.bucket_count {
+            let mut old = self.buckets.pop_front().unwrap();
+            self.drop_old(old.usage());
+            self.old_bucket.extend(old.drain());
+            self.drop_old(0);
+            old
+        } else {
+            Bucket::new()
+        };
+
+        let mut current_bucket = new_bucket;
+        mem::swap(&mut current_bucket, &mut self.next_bucket);
+
+        self.buckets.push_back(current_bucket);
+        self.rotations += 1;
+    }
+}
+
+/// Histories are equal if they have the same configuration and the same keys
+/// in the same buckets.
+///
+/// Rotation count is not compared.
+impl<K> PartialEq for History<K> where K: Eq + Hash {
+    fn eq(&self, other: &History<K>) -> bool {
+        self.max_bucket_usage == other.max_bucket_usage
+            && self.bucket_count == other.bucket_count
+            && self.refresh_policy == other.refresh_policy
+            && self.old_bucket_cap == other.old_bucket_cap
+            && self.usage == other.usage
+            && self.next_bucket == other.next_bucket
+            && self.buckets == other.buckets
+            && self.old_bucket == other.old_bucket
+            && self.protected == other.protected
+    }
+}
+
+impl<K> Eq for History<K> where K: Eq + Hash {}
+
+/// Prints bucket usages from the oldest, not the keys.
+impl<K> fmt::Debug for History<K>
+    where K: Eq + Hash + Clone
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("History")
+            .field("usage", &self.usage)
+            .field("buckets", &self.simple_usage())
+            .field("protected", &self.protected)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod bucket_test {
+    use super::*;
+
+    #[test]
+    fn usage_is_zero() {
+        let b = Bucket::<u32>::new();
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn store_correct_usage() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        assert_eq!(2, b.usage());
+        b.insert(2, 3);
+        assert_eq!(5, b.usage());
+    }
+
+    #[test]
+    fn usage_of_matches_usage_delta() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        let before = b.usage();
+        b.insert(1, 5);
+        assert_eq!(Some(b.usage() - before), b.usage_of(&1));
+        assert_eq!(Some(2), b.usage_of(&3));
+        assert_eq!(None, b.usage_of(&4));
+    }
+
+    #[test]
+    fn get_or_insert_with_keeps_existing_value() {
+        let mut b = Bucket::new();
+        b.insert(1, 2);
+
+        assert_eq!(2, b.get_or_insert_with(1, || panic!("must not be called")));
+        assert_eq!(2, b.usage());
+    }
+
+    #[test]
+    fn get_or_insert_with_inserts_missing_value() {
+        let mut b = Bucket::new();
+        b.insert(1, 2);
+
+        assert_eq!(5, b.get_or_insert_with(2, || 5));
+        assert_eq!(7, b.usage());
+        assert_eq!(2, b.len());
+    }
+
+    #[test]
+    fn counts_items() {
+        let mut b = Bucket::new();
+        assert!(b.is_empty());
+        b.insert(3, 2);
+        b.insert(1, 2);
+        assert_eq!(2, b.len());
+        assert!(!b.is_empty());
+    }
+
+    #[test]
+    fn debug_prints_counts() {
+        let mut b = Bucket::new();
+        b.insert(1, 2);
+        b.insert(2, 3);
+        assert_eq!("Bucket { items: 2, usage: 5 }", format!("{:?}", b));
+    }
+
+    #[test]
+    fn merges_other_bucket() {
+        let mut a = Bucket::new();
+        a.insert(1, 2);
+        a.insert(2, 3);
+        let mut b = Bucket::new();
+        b.insert(2, 5);
+        b.insert(3, 1);
+
+        a.merge(b);
+
+        assert_eq!(3, a.len());
+        assert_eq!(Some(2), a.usage_of(&1));
+        assert_eq!(Some(5), a.usage_of(&2));
+        assert_eq!(Some(1), a.usage_of(&3));
+        assert_eq!(8, a.usage());
+    }
+
+    #[test]
+    fn pops_all_items() {
+        let mut b = Bucket::new();
+        b.insert(1, 2);
+        b.insert(2, 3);
+
+        let mut popped = vec![b.pop().unwrap(), b.pop().unwrap()];
+        popped.sort();
+        assert_eq!(vec![(1, 2), (2, 3)], popped);
+        assert_eq!(None, b.pop());
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn pops_while_usage_is_high() {
+        let mut b = Bucket::new();
+        for key in 0..10 {
+            b.insert(key, 2);
+        }
+
+        let mut popped = Vec::new();
+        b.pop_while(|usage| usage > 13, |key, bytes| popped.push((key, bytes)));
+        assert_eq!(4, popped.len());
+        assert_eq!(12, b.usage());
+        assert!(popped.iter().all(|&(key, _)| !b.contains(&key)));
+
+        b.pop_while(|_| true, |_, _| ());
+        assert!(b.is_empty());
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn equal_if_same_items() {
+        let mut a = Bucket::new();
+        a.insert(1, 2);
+        a.insert(2, 3);
+        let mut b = Bucket::new();
+        b.insert(2, 3);
+        b.insert(1, 2);
+        assert!(a == b);
+
+        b.insert(1, 1);
+        assert!(a != b);
+    }
+
+    #[test]
+    fn usage_saturates() {
+        let mut b = Bucket::new();
+        b.insert(1, MAX_USAGE - 1);
+        b.insert(2, 5);
+        assert_eq!(MAX_USAGE, b.usage());
+        b.insert(2, MAX_USAGE);
+        assert_eq!(MAX_USAGE, b.usage());
+        b.remove(&1);
+        b.remove(&2);
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn contains_stored() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        assert!(b.contains(&3));
+    }
+
+    #[test]
+    fn not_contains_removed() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        b.remove(&3);
+        assert!(!b.contains(&3));
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn is_iteratable() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        b.insert(1, 1);
+
+        let all = b.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        assert!(all.contains(&3));
+        assert!(all.contains(&1));
+    }
+
+    #[test]
+    fn take_returns_value() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        assert_eq!(Some(2), b.take(&3));
+        assert_eq!(None, b.take(&3));
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn works_after_reserve() {
+        let mut b = Bucket::new();
+        b.reserve(2);
+        for i in 0..5 {
+            b.insert(i, 1);
+        }
+        assert!(b.contains(&0));
+        assert!(b.contains(&4));
+        assert_eq!(5, b.usage());
+    }
+
+    #[test]
+    fn drain_returns_all() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        b.insert(1, 1);
+
+        let mut all = b.drain().collect::<Vec<_>>();
+        all.sort();
+        assert_eq!(vec![(1, 1), (3, 2)], all);
+        assert_eq!(0, b.usage());
+        assert!(!b.contains(&3));
+    }
+
+    #[test]
+    fn take_entry_returns_key_and_value() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        assert_eq!(Some((3, 2)), b.take_entry(&3));
+        assert_eq!(None, b.take_entry(&3));
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn not_contains_cleared() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+        b.clear();
+        assert!(!b.contains(&3));
+        assert_eq!(0, b.usage());
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+
+        let mut c = b.clone();
+        c.insert(1, 1);
+
+        assert_eq!(2, b.usage());
+        assert!(!b.contains(&1));
+        assert_eq!(3, c.usage());
+        assert!(c.contains(&3));
+    }
+
+    #[test]
+    fn can_be_extended() {
+        let mut b = Bucket::new();
+        b.insert(3, 2);
+
+        let mut c = Bucket::new();
+        c.insert(1, 1);
+
+        b.extend(c.iter().map(|(k, v)| (*k, *v)));
+
+        assert!(b.contains(&3));
+        assert!(b.contains(&1));
+        assert_eq!(3, b.usage());
+
+        assert!(c.contains(&1));
+        assert_eq!(1, c.usage());
+    }
+}
+
+#[cfg(test)]
+mod history_test {
+    use std::cell::Cell;
+    use std::collections::BTreeSet;
+    use std::hash::Hash;
     use super::*;
 
+    thread_local!(static CLONES: Cell<usize> = const { Cell::new(0) });
+
+    #[derive(Debug, Eq, PartialEq, Hash)]
+    struct CountedKey(u32);
+
+    impl Clone for CountedKey {
+        fn clone(&self) -> CountedKey {
+            CLONES.with(|c| c.set(c.get() + 1));
+            CountedKey(self.0)
+        }
+    }
+
+    fn clones() -> usize {
+        CLONES.with(|c| c.get())
+    }
+
+    #[test]
+    fn spills_oldest() {
+        let mut h = History::new(2, 2);
+        h.hit(1, 2);
+        h.hit(2, 2);
+        h.hit(3, 2);
+        h.hit(4, 2);
+
+        assert_eq!(vec![2, 2, 2, 2], h.simple_usage());
+        assert_eq!(8, h.usage());
+
+        assert_eq!(vec![1], spill_and_get_sorted(&mut h));
+
+        assert_eq!(vec![0, 2, 2, 2], h.simple_usage());
+        assert_eq!(6, h.usage());
+    }
+
+    #[test]
+    fn supports_oversized() {
+        let mut h = History::new(2, 2);
+        h.hit(1, 4);
+        h.hit(2, 5);
+        h.hit(3, 6);
+        h.hit(4, 7);
+
+        assert_eq!(vec![4, 5, 6, 7], h.simple_usage());
+        assert_eq!(22, h.usage());
+
+        assert_eq!(vec![1], spill_and_get_sorted(&mut h));
+
+        assert_eq!(vec![0, 5, 6, 7], h.simple_usage());
+        assert_eq!(18, h.usage());
+    }
+
+    #[test]
+    fn supports_small() {
+        let mut h = History::new(2, 1);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.hit(3, 1);
+
+        assert_eq!(vec![0, 2, 1], h.simple_usage());
+        assert_eq!(3, h.usage());
+
+        h.hit(4, 1);
+        h.hit(5, 1);
+
+        assert_eq!(vec![2, 2, 1], h.simple_usage());
+        assert_eq!(5, h.usage());
+
+        assert_eq!(vec![1, 2], spill_and_get_sorted(&mut h));
+
+        assert_eq!(vec![0, 2, 1], h.simple_usage());
+        assert_eq!(3, h.usage());
+    }
+
+    #[test]
+    fn digs_out_when_used_again() {
+        let mut h = History::new(2, 1);
+        h.hit(1, 1);
+        h.hit(2, 1);
+
+        h.hit(1, 1);
+
+        assert_eq!(vec![0, 1, 1], h.simple_usage());
+        assert_eq!(2, h.usage());
+    }
+
+    #[test]
+    fn removes_recent() {
+        let mut h = History::new(2, 1);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.hit(3, 1);
+
+        h.remove(&3);
+
+        assert_eq!(vec![0, 2, 0], h.simple_usage());
+        assert_eq!(2, h.usage());
+    }
+
+    #[test]
+    fn removes_burried() {
+        let mut h = History::new(2, 1);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.hit(3, 1);
+
+        h.remove(&1);
+        h.remove(&2);
+
+        assert_eq!(vec![0, 0, 1], h.simple_usage());
+        assert_eq!(1, h.usage());
+    }
+
+    #[test]
+    fn removes_old() {
+        let mut h = History::new(2, 1);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.hit(3, 1);
+        h.hit(4, 1);
+
+        h.remove(&1);
+        h.remove(&2);
+
+        assert_eq!(vec![0, 0, 2], h.simple_usage());
+        assert_eq!(2, h.usage());
+    }
+
+    #[test]
+    fn config_is_equivalent_to_new() {
+        let mut h = History::with_config(BucketConfig {
+            max_bucket_usage: 2,
+            bucket_count: 1,
+        });
+        let mut n = History::new(2, 1);
+        assert_eq!(n.config(), h.config());
+
+        for k in 1..6 {
+            h.hit(k, 1);
+            n.hit(k, 1);
+        }
+
+        assert_eq!(n.simple_usage(), h.simple_usage());
+        assert_eq!(spill_and_get_sorted(&mut n), spill_and_get_sorted(&mut h));
+    }
+
+    #[test]
+    fn always_refresh_promotes_same_size_hit() {
+        let mut h = History::new(2, 2);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.advance();
+        h.hit(1, 1);
+
+        assert_eq!(vec![0, 1, 1], h.simple_usage());
+    }
+
+    #[test]
+    fn only_if_changed_refresh_keeps_same_size_hit() {
+        let mut h = History::new(2, 2);
+        h.set_refresh_policy(RefreshPolicy::OnlyIfChanged);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.advance();
+        h.hit(1, 1);
+
+        assert_eq!(vec![0, 2, 0], h.simple_usage());
+
+        h.hit(1, 2);
+        assert_eq!(vec![0, 1, 2], h.simple_usage());
+        assert_eq!(Some(2), h.usage_of(&1));
+    }
+
+    #[test]
+    fn reports_item_counts_per_bucket() {
+        let mut h = History::new(4, 2);
+        h.hit(1, 2);
+        h.hit(2, 2);
+        h.hit(3, 1);
+        h.hit(4, 1);
+        h.hit(5, 1);
+        h.hit(6, 1);
+        h.hit(7, 3);
+        h.hit(8, 1);
+        h.hit(9, 4);
+
+        let usage = h.detailed_usage_full();
+        assert_eq!(
+            vec![(4, 2), (4, 4), (4, 2), (4, 1)],
+            usage.iter().map(|u| (u.bytes, u.items)).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![None, Some(4), Some(4), Some(4)], usage.iter().map(|u| u.max).collect::<Vec<_>>());
+        assert_eq!(
+            h.detailed_usage(),
+            usage.iter().map(|u| (u.bytes, u.max)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn usage_saturates() {
+        let mut h = History::with_eviction_policy(MAX_USAGE, 2, EvictionPolicy::Segmented);
+        h.hit(1, MAX_USAGE - 1);
+        h.hit(2, MAX_USAGE - 1);
+        h.hit(2, MAX_USAGE);
+        h.hit(3, 10);
+        assert_eq!(MAX_USAGE, h.usage());
+
+        h.advance();
+        h.advance();
+        h.advance();
+        h.hit(1, 1);
+        assert!(h.contains(&1));
+        assert_eq!(Some(1), h.usage_of(&1));
+    }
+
+    #[test]
+    fn spills_one_old_element() {
+        let mut h = History::new(2, 1);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        h.hit(3, 1);
+        h.hit(4, 1);
+        h.hit(5, 1);
+        assert_eq!(vec![2, 2, 1], h.simple_usage());
+
+        let (key, bytes) = h.spill_one().unwrap();
+        assert!(key == 1 || key == 2);
+        assert_eq!(1, bytes);
+        assert_eq!(vec![1, 2, 1], h.simple_usage());
+        assert_eq!(4, h.usage());
+
+        h.spill_one().unwrap();
+        assert_eq!(None, h.spill_one());
+        assert_eq!(3, h.usage());
+    }
+
+    #[test]
+    fn spills_one_by_one_around_hits() {
+        let mut h = History::new(2, 1);
+        for k in 1..9 {
+            h.hit(k, 1);
+        }
+        assert_eq!(vec![4, 2, 2], h.simple_usage());
+
+        let (first, _) = h.spill_one().unwrap();
+        let hit = (1..5).find(|&k| k != first).unwrap();
+        h.hit(hit, 1);
+
+        let mut expected: Vec<u8> = h.peek_spillable().into_iter().map(|(&k, _)| k).collect();
+        assert!(!expected.contains(&hit));
+        assert!(expected.len() > 2);
+
+        let mut spilled = Vec::new();
+        while let Some((key, _)) = h.spill_one() {
+            spilled.push(key);
+        }
+        expected.sort();
+        spilled.sort();
+
+        assert_eq!(expected, spilled);
+        assert!(h.contains(&hit));
+        assert_eq!(7 - spilled.len() as u64, h.usage());
+    }
+
+    #[test]
+    fn peeking_spillable_does_not_remove() {
+        let mut h = History::new(2, 1);
+        for k in 1..6 {
+            h.hit(k, 1);
+        }
+
+        let mut peeked: Vec<(u8, u64)> = h.peek_spillable().into_iter().map(|(&k, b)| (k, b)).collect();
+        peeked.sort();
+        assert_eq!(vec![(1, 1), (2, 1)], peeked);
+        assert_eq!(5, h.usage());
+
+        let mut spilled = Vec::new();
+        h.spill(&mut spilled);
+        spilled.sort();
+        assert_eq!(peeked, spilled);
+        assert!(h.peek_spillable().is_empty());
+    }
+
+    #[test]
+    fn debug_prints_bucket_usages() {
+        let mut h = History::new(2, 1);
+        for k in 1..6 {
+            h.hit(k, 1);
+        }
+        assert_eq!("History { usage: 5, buckets: [2, 2, 1], protected: None }", format!("{:?}", h));
+    }
+
+    #[test]
+    fn remove_returns_tracked_size() {
+        let mut h = History::with_eviction_policy(4, 2, EvictionPolicy::Segmented);
+        h.hit(1, 3);
+        h.hit(2, 2);
+        h.hit(2, 2);
+        for k in 3..10 {
+            h.hit(k, 1);
+        }
+
+        assert_eq!(Some(3), h.remove_returning_size(&1));
+        assert_eq!(Some(2), h.remove_returning_size(&2));
+        assert_eq!(Some(1), h.remove_returning_size(&9));
+        assert_eq!(None, h.remove_returning_size(&1));
+        assert_eq!(None, h.remove_returning_size(&100));
+        assert_eq!(6, h.usage());
+    }
+
     #[test]
-    fn spills_oldest() {
-        let mut h = History::new(2, 2);
-        h.hit(1, 2);
-        h.hit(2, 2);
-        h.hit(3, 2);
-        h.hit(4, 2);
+    fn hit_reports_outcome() {
+        let mut h = History::new(2, 1);
+        assert_eq!(HitOutcome::Inserted, h.hit(1, 1));
+        assert_eq!(HitOutcome::Refreshed, h.hit(1, 1));
+        assert_eq!(HitOutcome::Inserted, h.hit(2, 1));
+        assert_eq!(HitOutcome::InsertedWithRotation, h.hit(3, 1));
+        assert_eq!(HitOutcome::Inserted, h.hit(4, 1));
+        assert_eq!(HitOutcome::RefreshedWithRotation, h.hit_ref(&1, 1));
+        assert_eq!(HitOutcome::Refreshed, h.hit_ref(&1, 1));
+        assert!(!h.hit(5, 1).rotated());
+        assert!(h.hit(6, 1).rotated());
+    }
 
-        assert_eq!(vec![2, 2, 2, 2], h.simple_usage());
-        assert_eq!(8, h.usage());
+    #[test]
+    fn hit_reports_rotation_exactly_when_bucket_is_buried() {
+        let mut h = History::with_eviction_policy(3, 2, EvictionPolicy::Segmented);
+        for k in 0..50u64 {
+            let rotations = h.rotation_count();
+            let outcome = h.hit(k % 7, k % 3 + 1);
+            assert_eq!(h.rotation_count() != rotations, outcome.rotated());
+        }
+    }
 
-        assert_eq!(vec![1], spill_and_get_sorted(&mut h));
+    #[test]
+    fn old_bucket_usage_stays_under_cap() {
+        use std::sync::Mutex;
+
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let mut h = History::new(4, 2);
+        h.set_old_bucket_cap(Some(6));
+        {
+            let dropped = dropped.clone();
+            h.on_old_drop(move |k, bytes| dropped.lock().unwrap().push((k, bytes)));
+        }
 
-        assert_eq!(vec![0, 2, 2, 2], h.simple_usage());
-        assert_eq!(6, h.usage());
+        for k in 0..100u64 {
+            h.hit(k, k % 3 + 1);
+            assert!(h.simple_usage()[0] <= 6);
+        }
+
+        let dropped = dropped.lock().unwrap();
+        assert!(!dropped.is_empty());
+        assert!(dropped.iter().all(|&(k, _)| !h.contains(&k)));
+        let tracked: u64 = (0..100u64).filter_map(|k| h.usage_of(&k)).sum();
+        assert_eq!(tracked, h.usage());
     }
 
     #[test]
-    fn supports_oversized() {
-        let mut h = History::new(2, 2);
-        h.hit(1, 4);
-        h.hit(2, 5);
-        h.hit(3, 6);
-        h.hit(4, 7);
+    fn old_bucket_cap_drops_longest_old_keys_first() {
+        let mut h = History::new(2, 1);
+        h.set_old_bucket_cap(Some(2));
+        for k in 1..9 {
+            h.hit(k, 1);
+        }
 
-        assert_eq!(vec![4, 5, 6, 7], h.simple_usage());
-        assert_eq!(22, h.usage());
+        assert_eq!(vec![3, 4], spill_and_get_sorted(&mut h));
 
-        assert_eq!(vec![1], spill_and_get_sorted(&mut h));
+        h.set_old_bucket_cap(Some(0));
+        h.advance();
+        assert!(spill_and_get_sorted(&mut h).is_empty());
+    }
 
-        assert_eq!(vec![0, 5, 6, 7], h.simple_usage());
-        assert_eq!(18, h.usage());
+    #[test]
+    fn iterates_by_recency() {
+        let mut h = History::new(2, 1);
+        for k in 1..7 {
+            h.hit(k, 1);
+        }
+
+        let mut keys: Vec<(u64, usize)> = h.iter_by_recency().map(|(&k, _, i)| (k, i)).collect();
+        keys.sort_by_key(|&(k, i)| (i, k));
+        assert_eq!(vec![(5, 0), (6, 0), (3, 1), (4, 1), (1, 2), (2, 2)], keys);
     }
 
     #[test]
-    fn supports_small() {
+    fn clear_preserves_config() {
         let mut h = History::new(2, 1);
+        for k in 1..6 {
+            h.hit(k, 1);
+        }
+
+        h.clear();
+
+        assert_eq!(BucketConfig { max_bucket_usage: 2, bucket_count: 1 }, h.config());
+        assert_eq!(0, h.usage());
+        assert_eq!(2, h.rotation_count());
+    }
+
+    #[test]
+    fn reset_applies_new_config() {
+        let mut h = History::with_eviction_policy(2, 1, EvictionPolicy::Segmented);
+        h.set_refresh_policy(RefreshPolicy::OnlyIfChanged);
+        for k in 1..6 {
+            h.hit(k, 1);
+        }
+
+        let config = BucketConfig { max_bucket_usage: 4, bucket_count: 3 };
+        h.reset(config);
+
+        assert_eq!(config, h.config());
+        assert_eq!(0, h.usage());
+        assert_eq!(0, h.total_items());
+        assert_eq!(0, h.rotation_count());
+        assert_eq!(EvictionPolicy::Segmented, h.eviction_policy());
+        assert_eq!(RefreshPolicy::OnlyIfChanged, h.refresh_policy());
+
+        for k in 1..5 {
+            h.hit(k, 1);
+        }
+        assert_eq!(vec![0, 4], h.simple_usage());
+    }
+
+    #[test]
+    fn only_if_changed_still_promotes_to_protected() {
+        let mut h = History::with_eviction_policy(4, 2, EvictionPolicy::Segmented);
+        h.set_refresh_policy(RefreshPolicy::OnlyIfChanged);
+        h.hit(1, 1);
+        assert_eq!(0, h.protected_usage());
+
+        h.hit(1, 1);
+        assert_eq!(1, h.protected_usage());
+        assert_eq!(1, h.usage_of(&1).unwrap());
+
+        h.advance();
+        let rotations = h.rotation_count();
+        h.hit(1, 1);
+        assert_eq!(1, h.protected_usage());
+        assert_eq!(rotations, h.rotation_count());
+        assert_eq!(RefreshPolicy::OnlyIfChanged, h.protected.as_ref().unwrap().refresh_policy());
+    }
+
+    #[test]
+    fn compacts_sparse_buckets() {
+        let mut h = History::new(4, 4);
+        for k in 0..20 {
+            h.hit(k, 1);
+        }
+        for k in 0..20 {
+            if k % 4 != 0 {
+                h.remove(&k);
+            }
+        }
+        assert_eq!(vec![0, 1, 1, 1, 1, 1], h.simple_usage());
+
+        h.compact();
+
+        assert_eq!(vec![0, 4, 1], h.simple_usage());
+        assert_eq!(5, h.usage());
+        assert_eq!(5, h.total_items());
+
+        let mut spilled = Vec::new();
+        h.advance();
+        h.advance();
+        h.advance();
+        h.spill(&mut spilled);
+        assert!(spilled.is_empty());
+        h.advance();
+        h.spill(&mut spilled);
+        spilled.sort();
+        assert_eq!(vec![(0, 1), (4, 1), (8, 1), (12, 1)], spilled);
+    }
+
+    #[test]
+    fn equal_if_built_identically() {
+        let build = |keys: &[u8]| {
+            let mut h = History::new(2, 2);
+            for &k in keys {
+                h.hit(k, 1);
+            }
+            h
+        };
+
+        assert!(build(&[1, 2, 3, 4, 5]) == build(&[1, 2, 3, 4, 5]));
+        assert!(build(&[1, 2, 3, 4, 5]) != build(&[1, 3, 2, 4, 5]));
+        assert!(build(&[1, 2, 3]) != build(&[1, 2, 3, 4]));
+        assert!(History::<u8>::new(2, 2) != History::<u8>::new(2, 3));
+        assert!(History::<u8>::new(2, 2) != History::<u8>::with_eviction_policy(2, 2, EvictionPolicy::Segmented));
+    }
+
+    #[test]
+    fn reports_capacity() {
+        assert_eq!(15, History::<u8>::new(5, 2).capacity());
+        assert_eq!(4, History::<u8>::new(4, 0).capacity());
+        assert_eq!(0, History::<u8>::new(0, 10).capacity());
+        assert_eq!(2560, History::<u8>::with_eviction_policy(10, 255, EvictionPolicy::Segmented).capacity());
+        assert_eq!(u64::MAX, History::<u8>::new(u64::MAX, 2).capacity());
+    }
+
+    #[test]
+    fn counts_total_items() {
+        let mut h = History::with_eviction_policy(2, 1, EvictionPolicy::Segmented);
+        assert_eq!(0, h.total_items());
+
+        h.hit(1, 1);
         h.hit(1, 1);
         h.hit(2, 1);
         h.hit(3, 1);
+        h.hit(4, 1);
+        h.hit(5, 1);
+        assert_eq!(5, h.total_items());
 
-        assert_eq!(vec![0, 2, 1], h.simple_usage());
-        assert_eq!(3, h.usage());
+        h.remove(&3);
+        assert_eq!(4, h.total_items());
+    }
+
+    #[test]
+    fn counts_rotations() {
+        let mut h = History::new(2, 2);
+        h.hit(1, 1);
+        h.hit(2, 1);
+        assert_eq!(0, h.rotation_count());
 
+        h.hit(3, 1);
         h.hit(4, 1);
         h.hit(5, 1);
+        assert_eq!(2, h.rotation_count());
+    }
 
-        assert_eq!(vec![2, 2, 1], h.simple_usage());
-        assert_eq!(5, h.usage());
+    #[test]
+    fn advance_moves_everything_to_old() {
+        let mut h = History::new(10, 2);
+        h.hit(1, 1);
+        h.hit(2, 1);
+
+        h.advance();
+        assert_eq!(vec![0, 2, 0], h.simple_usage());
+
+        h.advance();
+        assert_eq!(vec![0, 2, 0, 0], h.simple_usage());
+
+        h.advance();
+        assert_eq!(vec![2, 0, 0, 0], h.simple_usage());
+        assert_eq!(2, h.usage());
 
         assert_eq!(vec![1, 2], spill_and_get_sorted(&mut h));
+    }
 
-        assert_eq!(vec![0, 2, 1], h.simple_usage());
-        assert_eq!(3, h.usage());
+    #[test]
+    fn merges_other_history() {
+        let mut h = History::new(2, 2);
+        h.hit(1, 1);
+        h.hit(2, 1);
+
+        let mut other = History::new(2, 2);
+        other.hit(2, 3);
+        other.hit(3, 1);
+        other.hit(4, 1);
+        other.hit(5, 1);
+
+        h.merge_from(other);
+
+        assert_eq!(7, h.usage());
+        assert_eq!(vec![1, 2, 3, 4, 5], spill_all_and_get_sorted(&mut h));
     }
 
     #[test]
-    fn digs_out_when_used_again() {
+    fn merge_keeps_recency_of_other() {
+        let mut h = History::new(1, 1);
+
+        let mut other = History::new(1, 1);
+        other.hit(1, 1);
+        other.hit(2, 1);
+        other.hit(3, 1);
+
+        h.merge_from(other);
+
+        assert_eq!(vec![1], spill_and_get_sorted(&mut h));
+    }
+
+    #[test]
+    fn works_after_reserve() {
         let mut h = History::new(2, 1);
+        h.reserve(2);
         h.hit(1, 1);
         h.hit(2, 1);
+        h.hit(3, 1);
+
+        assert_eq!(vec![0, 2, 1], h.simple_usage());
+        assert_eq!(3, h.usage());
+    }
 
+    #[test]
+    fn cached_usage_matches_summed_usage() {
+        let mut h = History::new(3, 2);
         h.hit(1, 1);
+        h.hit(2, 2);
+        h.hit(3, 3);
+        h.hit(1, 2);
+        h.hit(4, 1);
+        h.hit(5, 4);
+        assert_eq!(h.simple_usage().iter().sum::<u64>(), h.usage());
 
-        assert_eq!(vec![0, 1, 1], h.simple_usage());
-        assert_eq!(2, h.usage());
+        h.remove(&2);
+        h.remove(&5);
+        h.remove(&42);
+        h.hit(4, 3);
+        h.advance();
+        assert_eq!(h.simple_usage().iter().sum::<u64>(), h.usage());
+
+        h.hit(6, 3);
+        h.hit(7, 3);
+        h.hit(8, 3);
+        h.spill(&mut Vec::new());
+        assert_eq!(h.simple_usage().iter().sum::<u64>(), h.usage());
+
+        h.clear();
+        assert_eq!(0, h.usage());
+        assert_eq!(h.simple_usage().iter().sum::<u64>(), h.usage());
     }
 
     #[test]
-    fn removes_recent() {
+    fn clone_is_independent() {
         let mut h = History::new(2, 1);
         h.hit(1, 1);
         h.hit(2, 1);
         h.hit(3, 1);
 
-        h.remove(&3);
+        let mut c = h.clone();
+        assert_eq!(h.simple_usage(), c.simple_usage());
+        assert_eq!(h.usage(), c.usage());
 
-        assert_eq!(vec![0, 2, 0], h.simple_usage());
-        assert_eq!(2, h.usage());
+        c.hit(4, 1);
+        c.hit(5, 1);
+        c.remove(&3);
+
+        assert_eq!(vec![0, 2, 1], h.simple_usage());
+        assert_eq!(3, h.usage());
+        assert_eq!(vec![1, 2], spill_and_get_sorted(&mut c));
+        assert!(spill_and_get_sorted(&mut h).is_empty());
     }
 
     #[test]
-    fn removes_burried() {
+    fn hit_ref_clones_only_new_keys() {
+        let mut h = History::new(2, 2);
+        let key = CountedKey(1);
+
+        let before = clones();
+        h.hit_ref(&key, 1);
+        assert_eq!(before + 1, clones());
+
+        h.hit_ref(&key, 1);
+        h.hit_ref(&key, 2);
+        h.advance();
+        h.hit_ref(&key, 2);
+        h.advance();
+        h.advance();
+        h.advance();
+        h.hit_ref(&key, 2);
+        assert_eq!(before + 1, clones());
+
+        assert_eq!(vec![0, 0, 0, 2], h.simple_usage());
+    }
+
+    #[test]
+    fn segmented_hit_ref_clones_only_new_keys() {
+        let mut h = History::with_eviction_policy(2, 2, EvictionPolicy::Segmented);
+        let key = CountedKey(1);
+
+        let before = clones();
+        h.hit_ref(&key, 1);
+        h.hit_ref(&key, 1);
+        h.hit_ref(&key, 1);
+        assert_eq!(before + 1, clones());
+        assert_eq!(1, h.protected_usage());
+    }
+
+    #[test]
+    fn recency_spills_twice_hit_key_on_flood() {
         let mut h = History::new(2, 1);
         h.hit(1, 1);
-        h.hit(2, 1);
-        h.hit(3, 1);
+        h.hit(1, 1);
+        for k in 2..10 {
+            h.hit(k, 1);
+        }
 
-        h.remove(&1);
-        h.remove(&2);
+        assert!(spill_and_get_sorted(&mut h).contains(&1));
+    }
 
-        assert_eq!(vec![0, 0, 1], h.simple_usage());
+    #[test]
+    fn segmented_protects_twice_hit_key_from_flood() {
+        let mut h = History::with_eviction_policy(2, 1, EvictionPolicy::Segmented);
+        h.hit(1, 1);
+        h.hit(1, 1);
+        for k in 2..10 {
+            h.hit(k, 1);
+        }
+
+        assert_eq!(vec![2, 3, 4, 5], spill_and_get_sorted(&mut h));
+        assert!(h.contains(&1));
+        assert_eq!(1, h.protected_usage());
+        assert_eq!(5, h.usage());
+    }
+
+    #[test]
+    fn segmented_demotes_aged_protected_keys() {
+        let mut h = History::with_eviction_policy(2, 1, EvictionPolicy::Segmented);
+        h.hit(1, 1);
+        h.hit(1, 1);
+
+        h.advance();
+        h.advance();
+        assert_eq!(0, h.protected_usage());
         assert_eq!(1, h.usage());
+
+        h.advance();
+        h.advance();
+        assert_eq!(vec![1], spill_and_get_sorted(&mut h));
+        assert_eq!(0, h.usage());
     }
 
     #[test]
-    fn removes_old() {
-        let mut h = History::new(2, 1);
+    fn segmented_removes_protected_keys() {
+        let mut h = History::with_eviction_policy(2, 1, EvictionPolicy::Segmented);
+        h.hit(1, 1);
         h.hit(1, 1);
-        h.hit(2, 1);
-        h.hit(3, 1);
-        h.hit(4, 1);
 
-        h.remove(&1);
-        h.remove(&2);
+        assert!(h.remove(&1));
+        assert!(!h.contains(&1));
+        assert_eq!(0, h.usage());
+    }
 
-        assert_eq!(vec![0, 0, 2], h.simple_usage());
-        assert_eq!(2, h.usage());
+    fn spill_all_and_get_sorted<V>(history: &mut History<V>) -> Vec<V> where V: Eq + Hash + Clone + Ord {
+        for _ in 0..history.simple_usage().len() {
+            history.advance();
+        }
+        spill_and_get_sorted(history)
     }
 
     fn spill_and_get_sorted<V>(history: &mut History<V>) -> Vec<V> where V: Eq + Hash + Clone + Ord {
         let mut res = BTreeSet::new();
         history.spill(&mut res);
 
-        res.iter().map(|&(ref k, _)| k.clone()).collect()
+        res.iter().map(|(k, _)| k.clone()).collect()
+    }
+
+    #[test]
+    fn hit_many_matches_hit_loop() {
+        let hits = (0..40u32).map(|i| (i % 13, (i % 5 + 1) as u64)).collect::<Vec<_>>();
+
+        let mut looped = History::new(6, 3);
+        for &(key, bytes) in &hits {
+            looped.hit(key, bytes);
+        }
+
+        let mut batched = History::new(6, 3);
+        batched.hit_many(hits);
+
+        assert_eq!(looped, batched);
+        assert_eq!(looped.simple_usage(), batched.simple_usage());
+    }
+
+    #[test]
+    fn hit_many_matches_hit_loop_for_random_batches() {
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for &bucket_count in &[0, 1, 3] {
+            for &policy in &[RefreshPolicy::Always, RefreshPolicy::OnlyIfChanged] {
+                let mut looped = History::new(10, bucket_count);
+                looped.set_refresh_policy(policy);
+                let mut batched = looped.clone();
+
+                for _ in 0..20 {
+                    let len = next() % 30;
+                    let hits = (0..len)
+                        .map(|_| ((next() % 25) as u32, next() % 4 + (next() % 2) * 10))
+                        .collect::<Vec<_>>();
+
+                    for &(key, bytes) in &hits {
+                        looped.hit(key, bytes);
+                    }
+                    batched.hit_many(hits);
+
+                    assert!(looped == batched);
+                    assert_eq!(looped.usage(), batched.usage());
+                    assert_eq!(looped.rotation_count(), batched.rotation_count());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hottest_and_coldest_come_from_newest_and_oldest_buckets() {
+        let mut h = History::new(2, 2);
+        assert_eq!(None, h.hottest());
+        assert_eq!(None, h.coldest());
+
+        for key in 0..10 {
+            h.hit(key, 1);
+        }
+
+        assert!([8, 9].contains(h.hottest().unwrap()));
+        assert!([0, 1, 2, 3].contains(h.coldest().unwrap()));
+
+        h.spill(&mut Vec::new());
+        assert!([4, 5].contains(h.coldest().unwrap()));
+    }
+
+    #[test]
+    fn usage_iterators_match_vecs() {
+        let mut h = History::new(3, 3);
+        for key in 0..20 {
+            h.hit(key, key % 3 + 1);
+        }
+
+        assert_eq!(h.simple_usage(), h.simple_usage_iter().collect::<Vec<_>>());
+        assert_eq!(h.detailed_usage(), h.detailed_usage_iter().collect::<Vec<_>>());
+        assert_eq!(None, h.detailed_usage_iter().next().unwrap().1);
+        assert_eq!(Some(3), h.detailed_usage_iter().last().unwrap().1);
+    }
+
+    #[test]
+    fn works_without_buried_buckets() {
+        let mut h = History::new(2, 0);
+        for key in 0..10 {
+            h.hit(key, 1);
+        }
+
+        assert_eq!(vec![8, 2], h.simple_usage());
+        assert_eq!(10, h.usage());
+        assert_eq!(2, h.capacity());
+
+        let mut spilled = Vec::new();
+        h.spill(&mut spilled);
+        assert_eq!(8, spilled.len());
+        assert!(h.contains(&8) && h.contains(&9));
+
+        h.advance();
+        assert_eq!(vec![2, 0], h.simple_usage());
+    }
+
+    #[test]
+    fn spill_to_stops_at_target() {
+        let mut h = History::new(4, 2);
+        for key in 0..12 {
+            h.hit(key, 1);
+        }
+
+        let mut spilled = Vec::new();
+        h.spill_to(7, |k, bytes| spilled.push((k, bytes)));
+        assert_eq!(7, h.usage());
+        assert_eq!(5, spilled.len());
+        assert!(spilled.iter().all(|&(k, _)| !h.contains(&k)));
+        assert!(h.contains(&11));
+
+        h.spill_to(0, |_, _| ());
+        assert_eq!(0, h.usage());
+        assert_eq!(0, h.total_items());
+    }
+
+    #[test]
+    fn spill_to_does_not_rotate() {
+        let mut h = History::new(4, 3);
+        for key in 0..14 {
+            h.hit(key, 1);
+        }
+        let rotations = h.rotation_count();
+
+        h.spill_to(5, |_, _| ());
+        assert_eq!(5, h.usage());
+        assert_eq!(rotations, h.rotation_count());
+        assert_eq!(vec![0, 0, 0, 3, 2], h.simple_usage());
+        assert!(h.contains(&12) && h.contains(&13));
+    }
+
+    #[test]
+    fn spill_to_except_keeps_key() {
+        let mut h = History::new(4, 2);
+        for key in 0..12 {
+            h.hit(key, 1);
+        }
+
+        let mut spilled = Vec::new();
+        h.spill_to_except(1, &0, |k, _| spilled.push(k));
+        assert_eq!(1, h.usage());
+        assert!(h.contains(&0));
+        assert!(!spilled.contains(&0));
+        assert_eq!(11, spilled.len());
+    }
+
+    #[test]
+    fn reclaimable_bytes_count_old_and_oldest_buried_bucket() {
+        let mut h = History::new(2, 3);
+        assert_eq!(0, h.reclaimable_bytes());
+
+        for key in 0..11 {
+            h.hit(key, 1);
+        }
+
+        assert_eq!(vec![4, 2, 2, 2, 1], h.simple_usage());
+        assert_eq!(6, h.reclaimable_bytes());
+        assert_eq!(11, h.usage());
+    }
+
+    #[test]
+    fn import_order_restores_exported_order() {
+        let mut h = History::new(2, 2);
+        for key in 0..9 {
+            h.hit(key, 1);
+        }
+        h.hit(0, 1);
+
+        let exported = h.export_order();
+        assert_eq!(9, exported.len());
+        assert!(exported[7..].contains(&(0, 1)));
+
+        let buckets_of = |h: &History<u32>| {
+            let mut res = h.iter_by_recency().map(|(&k, _, i)| (k, i)).collect::<Vec<_>>();
+            res.sort();
+            res
+        };
+        let before = buckets_of(&h);
+
+        h.clear();
+        h.import_order(exported);
+
+        let after = buckets_of(&h);
+        for (&(key, old_before), &(_, old_after)) in before.iter().zip(&after) {
+            for (&(other, other_before), &(_, other_after)) in before.iter().zip(&after) {
+                if old_before > other_before {
+                    assert!(old_after >= other_after, "{} is no longer older than {}", key, other);
+                }
+            }
+        }
+        assert_eq!(9, h.usage());
     }
 }
@@ -5,28 +5,55 @@
 <style>.sidebar { margin-top: 53px }</style>
 */
 
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+#[cfg(feature = "sysinfo")]
+extern crate sysinfo;
+
 pub mod path;
 pub mod mem;
 pub mod file;
 pub mod history;
+pub mod clock;
+mod sketch;
 
+use std::io;
+use std::sync::Arc;
 use std::io::Read;
 use std::io::Write;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum StoreResult {
     Stored,
+    /// Evicting old values did not free enough memory, because the rest are still hot.
     OutOfMemory,
+    /// Value is larger than the configured maximum entry size.
+    TooLarge,
+    /// Value is larger than the whole cache limit, so it can never be stored.
+    ExceedsLimit,
+    /// Admission policy rejected the value as less valuable than the values it would evict.
+    Rejected,
 }
 
 #[derive(Debug)]
 pub enum CreateReaderError {
     NotFound,
+    Io(io::Error),
 }
 
 #[derive(Debug)]
 pub enum CreateWriterError {
     OutOfMemory,
+    Io(io::Error),
+}
+
+impl From<CreateWriterError> for io::Error {
+    fn from(e: CreateWriterError) -> io::Error {
+        match e {
+            CreateWriterError::OutOfMemory => io::Error::other("out of memory"),
+            CreateWriterError::Io(e) => e,
+        }
+    }
 }
 
 pub trait RequiredBytes {
@@ -39,7 +66,150 @@ impl RequiredBytes for Vec<u8> {
     }
 }
 
+impl RequiredBytes for Arc<Vec<u8>> {
+    fn required_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl RequiredBytes for Box<[u8]> {
+    fn required_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+/// Memory taken by a cache key, for caches that count keys towards the limit.
+pub trait KeyBytes {
+    fn key_bytes(&self) -> u64;
+}
+
+impl<T: KeyBytes + ?Sized> KeyBytes for &T {
+    fn key_bytes(&self) -> u64 {
+        (**self).key_bytes()
+    }
+}
+
+impl KeyBytes for str {
+    fn key_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl KeyBytes for String {
+    fn key_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl KeyBytes for [u8] {
+    fn key_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl KeyBytes for Vec<u8> {
+    fn key_bytes(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+macro_rules! impl_key_bytes_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl KeyBytes for $t {
+                fn key_bytes(&self) -> u64 {
+                    ::std::mem::size_of::<$t>() as u64
+                }
+            }
+        )*
+    }
+}
+
+impl_key_bytes_for_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+/// Value that a cache can store and hand out as bytes.
+pub trait CacheValue: RequiredBytes {
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl CacheValue for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl CacheValue for Arc<Vec<u8>> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Boxed slice has no spare capacity, so it is leaner for values that do not change.
+impl CacheValue for Box<[u8]> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Reader over borrowed cached bytes.
+///
+/// Reads directly from the cache storage without copying the value.
+pub struct ReadGuard<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ReadGuard<'a> {
+    pub fn new(bytes: &'a [u8]) -> ReadGuard<'a> {
+        ReadGuard {
+            bytes,
+            position: 0,
+        }
+    }
+
+    /// Get all borrowed bytes, regardless of how much was already read.
+    pub fn as_slice(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> Read for ReadGuard<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut remaining = &self.bytes[self.position..];
+        let read = remaining.read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
 pub trait Cache<K> {
-    fn fetch<R: Read>(&self, key: K) -> Result<R, CreateReaderError>;
-    fn store<W: Write>(&self, key: K, required_mem: u64) -> Result<W, CreateWriterError>;
+    type Reader<'a>: Read where Self: 'a;
+    type Writer<'a>: Write where Self: 'a;
+
+    fn fetch(&self, key: K) -> Result<Self::Reader<'_>, CreateReaderError>;
+
+    /// Get a writer for a value that needs `required_mem` bytes.
+    ///
+    /// The value is stored by `flush`, which fails if it could not be stored. Writers
+    /// dropped without `flush` store nothing.
+    fn store(&mut self, key: K, required_mem: u64) -> Result<Self::Writer<'_>, CreateWriterError>;
+
+    /// Hint that the keys will be fetched soon.
+    ///
+    /// Does nothing by default.
+    fn prefetch(&mut self, _keys: &[K]) {}
+
+    /// Store all bytes through the writer returned by `store`.
+    fn store_bytes(&mut self, key: K, bytes: &[u8]) -> Result<(), CreateWriterError> {
+        let mut writer = self.store(key, bytes.len() as u64)?;
+        writer.write_all(bytes).map_err(CreateWriterError::Io)?;
+        writer.flush().map_err(CreateWriterError::Io)
+    }
+
+    /// Read all bytes from the reader returned by `fetch`.
+    fn fetch_bytes(&self, key: K) -> Result<Vec<u8>, CreateReaderError> {
+        let mut bytes = Vec::new();
+        self.fetch(key)?.read_to_end(&mut bytes).map_err(CreateReaderError::Io)?;
+        Ok(bytes)
+    }
 }
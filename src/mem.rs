@@ -1,7 +1,17 @@
 use std::collections::HashMap;
+use std::collections::hash_map;
+use std::collections::TryReserveError;
+use std::cmp::{ self, Reverse };
 use std::hash::Hash;
 use std::borrow::Borrow;
-use std::io::{ Read, Write };
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::ops::ControlFlow;
+use std::ops;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
 
 #[derive(Copy, Clone)]
 pub enum OutOfMemoryStrategy {
@@ -9,186 +19,2997 @@ pub enum OutOfMemoryStrategy {
     Restart,
 }
 
-use history::History;
+use history::{ History, BucketUsage };
+use clock::{ Clock, SystemClock };
+use sketch::FrequencySketch;
 use StoreResult;
 use Cache;
+use CacheValue;
+use KeyBytes;
+use ReadGuard;
 use CreateReaderError;
 use CreateWriterError;
 
+/// Bytes accounted for a single negative (known-absent) entry.
+pub const NEGATIVE_ENTRY_BYTES: u64 = 16;
+
+/// Result of a cache lookup that distinguishes remembered misses.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Lookup<'a> {
+    /// The value is cached.
+    Hit(&'a [u8]),
+    /// The key is known to be absent and the negative entry is still fresh.
+    NegativeHit,
+    /// Nothing is known about the key.
+    Miss,
+}
+
+/// When expired negative entries are removed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExpiryMode {
+    /// Expired entries are removed when they are looked up or evicted.
+    Lazy,
+    /// All expired entries are removed on every store.
+    Eager,
+}
+
+/// Decides whether a new value may evict old values to make room.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AdmissionPolicy {
+    /// Every value that fits after eviction is stored.
+    Always,
+    /// Key frequencies of stores and reads are estimated with a count-min sketch. A value
+    /// that needs eviction is rejected if its key is not more frequent than every key
+    /// that would be evicted.
+    ///
+    /// This keeps one-time scans from pushing out hot values, but a new key also has to
+    /// be seen a few times before it can replace values that were read before.
+    TinyLfu,
+}
+
+/// What `MemCache::shrink_to_with` does with values larger than the target usage.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ShrinkPolicy {
+    /// Evict them like any other value, so usage always ends at most at the target.
+    EvictOversized,
+    /// Keep them and evict everything else, because usage can not go below them anyway.
+    /// Usage stays above the target.
+    KeepOversized,
+}
+
+/// Reason `MemCache::set_limit_checked` rejected a limit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LimitError {
+    /// Limit of 0 would not allow storing anything.
+    Zero,
+    /// Limit is below the largest cached value, which would have to be evicted.
+    BelowLargestEntry { largest: u64 },
+}
+
+/// Cache operation reported to the observer, see `MemCache::set_observer`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheEvent<K> {
+    /// Value was read.
+    Get { key: K, hit: bool },
+    /// Value was stored, or failed to be stored.
+    Set { key: K, result: StoreResult },
+    /// Key was removed explicitly.
+    Remove { key: K, existed: bool },
+    /// Value or negative entry was evicted to make room.
+    Evict { key: K, bytes: u64 },
+}
+
+type Observer<K> = Box<dyn for<'a> FnMut(CacheEvent<&'a K>) + Send>;
+type Loader<K, V> = Arc<dyn Fn(&K) -> Option<V> + Send + Sync>;
+
+fn notify<K>(observer: &mut Option<Observer<K>>, event: CacheEvent<&K>) {
+    if let Some(ref mut observer) = *observer {
+        observer(event);
+    }
+}
+
+/// Metadata of a cached value.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EntryMeta {
+    /// Size of the value in bytes.
+    pub size: u64,
+    /// When the value was stored.
+    pub inserted_at: Instant,
+    /// How many times the value was read.
+    pub hits: u64,
+}
+
+/// Cache statistics.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// How many times history buckets were rotated.
+    pub rotations: u64,
+    /// Bytes of all values ever stored, including replaced ones.
+    pub stored_bytes: u64,
+    /// Bytes of all values ever evicted to make room.
+    pub evicted_bytes: u64,
+}
+
+/// Result of `MemCache::set_detailed`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SetOutcome<K, V = Vec<u8>> {
+    pub result: StoreResult,
+    /// Value that was replaced by the stored one.
+    pub replaced: Option<V>,
+    /// Values evicted to make room, in no particular order.
+    pub evicted: Vec<(K, V)>,
+}
+
+/// Values set during a batch, in the order their keys were first set.
+#[derive(Clone)]
+struct Batch<K, V> {
+    values: Vec<(K, Option<V>)>,
+    index: HashMap<K, usize>,
+}
+
+impl<K: Clone + Eq + Hash, V> Batch<K, V> {
+    fn new() -> Batch<K, V> {
+        Batch {
+            values: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        match self.index.get(&key) {
+            Some(&i) => self.values[i].1 = Some(value),
+            None => {
+                self.index.insert(key.clone(), self.values.len());
+                self.values.push((key, Some(value)));
+            },
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(i) = self.index.remove(key) {
+            self.values[i].1 = None;
+        }
+    }
+}
+
+/// Cached value together with its bookkeeping.
+#[derive(Clone)]
+struct Entry<V> {
+    value: V,
+    /// Bytes charged for the key, 0 if keys are not counted.
+    key_bytes: u64,
+    inserted_at: Instant,
+    hits: u64,
+}
+
+impl<V: CacheValue> Entry<V> {
+    fn new(value: V, key_bytes: u64, inserted_at: Instant) -> Entry<V> {
+        Entry {
+            value,
+            key_bytes,
+            inserted_at,
+            hits: 0,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.value.required_bytes()
+    }
+
+    /// Bytes charged against the limit.
+    fn charged(&self) -> u64 {
+        self.size().saturating_add(self.key_bytes)
+    }
+
+    fn meta(&self) -> EntryMeta {
+        EntryMeta {
+            size: self.size(),
+            inserted_at: self.inserted_at,
+            hits: self.hits,
+        }
+    }
+}
+
 /// In-memory cache.
-pub struct MemCache<K: Clone> {
+///
+/// Values are stored as `V`, which is `Vec<u8>` by default.
+pub struct MemCache<K: Clone, V = Vec<u8>> {
     limit: u64,
+    max_entry_size: Option<u64>,
+    eviction_batch: Option<usize>,
     history: History<K>,
-    items: HashMap<K, Vec<u8>>,
+    items: HashMap<K, Entry<V>>,
+    negative: HashMap<K, Instant>,
+    expiry_mode: ExpiryMode,
+    clock: Arc<dyn Clock>,
+    key_bytes: Option<fn(&K) -> u64>,
+    sketch: Option<FrequencySketch>,
+    batch: Option<Batch<K, V>>,
+    observer: Option<Observer<K>>,
+    loader: Option<Loader<K, V>>,
+    stored_bytes: u64,
+    evicted_bytes: u64,
+}
+
+/// The observer is not cloned.
+impl<K: Clone, V: Clone> Clone for MemCache<K, V> {
+    fn clone(&self) -> MemCache<K, V> {
+        MemCache {
+            limit: self.limit,
+            max_entry_size: self.max_entry_size,
+            eviction_batch: self.eviction_batch,
+            history: self.history.clone(),
+            items: self.items.clone(),
+            negative: self.negative.clone(),
+            expiry_mode: self.expiry_mode,
+            clock: self.clock.clone(),
+            key_bytes: self.key_bytes,
+            sketch: self.sketch.clone(),
+            batch: self.batch.clone(),
+            observer: None,
+            loader: self.loader.clone(),
+            stored_bytes: self.stored_bytes,
+            evicted_bytes: self.evicted_bytes,
+        }
+    }
+}
+
+/// Prints sizes and counts, not the values.
+impl<K, V> fmt::Debug for MemCache<K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemCache")
+            .field("limit", &self.limit)
+            .field("usage", &self.usage())
+            .field("items", &self.items.len())
+            .field("negative", &self.negative.len())
+            .finish()
+    }
+}
+
+/// In-memory cache that stores reference-counted values.
+///
+/// Values can be shared without copying the bytes, see `get_ref_counted`.
+pub type ArcMemCache<K> = MemCache<K, Arc<Vec<u8>>>;
+
+/// Read total memory with the `sysinfo` crate.
+#[cfg(feature = "sysinfo")]
+fn total_system_memory() -> Option<u64> {
+    use sysinfo::{ MemoryRefreshKind, RefreshKind, System };
+
+    let refresh = RefreshKind::nothing().with_memory(MemoryRefreshKind::nothing().with_ram());
+    match System::new_with_specifics(refresh).total_memory() {
+        0 => None,
+        total => Some(total),
+    }
+}
+
+#[cfg(not(feature = "sysinfo"))]
+fn total_system_memory() -> Option<u64> {
+    None
 }
 
-impl<K: Clone> MemCache<K>
+impl<K, V> MemCache<K, V>
     where
-        K: Eq + Hash
+        K: Clone + Eq + Hash,
+        V: CacheValue
 {
+    /// Counters per sketch row used by `AdmissionPolicy::TinyLfu`.
+    pub const ADMISSION_SKETCH_WIDTH: usize = 4096;
 
-    pub fn new(limit: u64) -> MemCache<K> {
-        let mut bucker_size = limit / 5;
+    /// Number of buried history buckets created by `new`.
+    pub const DEFAULT_BUCKET_COUNT: u64 = 2;
+
+    /// `new` sizes history buckets to `limit / DEFAULT_BUCKET_DIVISOR` bytes, at least 1.
+    pub const DEFAULT_BUCKET_DIVISOR: u64 = 5;
+
+    /// System memory assumed by `with_limit_fraction` when the total is unknown, 1 GiB.
+    pub const FALLBACK_SYSTEM_MEMORY: u64 = 1 << 30;
+
+    pub fn new(limit: u64) -> MemCache<K, V> {
+        let mut bucker_size = limit / Self::DEFAULT_BUCKET_DIVISOR;
         if bucker_size == 0 {
             bucker_size = 1;
         }
-        let bucket_count = 2;
+        let bucket_count = Self::DEFAULT_BUCKET_COUNT;
 
-        MemCache::<K> {
-            limit: limit,
+        MemCache::<K, V> {
+            limit,
+            max_entry_size: None,
+            eviction_batch: None,
             history: History::new(bucker_size, bucket_count),
             items: HashMap::new(),
+            negative: HashMap::new(),
+            expiry_mode: ExpiryMode::Lazy,
+            clock: Arc::new(SystemClock),
+            key_bytes: None,
+            sketch: None,
+            batch: None,
+            observer: None,
+            loader: None,
+            stored_bytes: 0,
+            evicted_bytes: 0,
         }
     }
 
-    pub fn with_capacity(limit: u64) -> MemCache<K> {
+    pub fn with_capacity(limit: u64) -> MemCache<K, V> {
         Self::new(limit)
     }
 
+    /// Create cache with the limit set to a `fraction` of total system memory.
+    ///
+    /// Total memory is read only with the `sysinfo` feature. Otherwise, or if it can not be
+    /// determined on this platform, `FALLBACK_SYSTEM_MEMORY` is assumed. Fraction is clamped to `0..=1`.
+    pub fn with_limit_fraction(fraction: f64) -> MemCache<K, V> {
+        let total = total_system_memory().unwrap_or(Self::FALLBACK_SYSTEM_MEMORY);
+        let fraction = if fraction > 0.0 { fraction.min(1.0) } else { 0.0 };
+        Self::new((total as f64 * fraction) as u64)
+    }
+
+    /// Reserve capacity for at least `additional` more values.
+    ///
+    /// Returns an error instead of aborting if allocation fails.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.items.try_reserve(additional)?;
+        self.history.try_reserve(additional)
+    }
+
+    /// Estimate how many items of `avg_item_size` fit into `limit` bytes.
+    ///
+    /// Bucket sizing does not add any overhead, so this is plain division.
+    /// Returns 0 for zero `avg_item_size`.
+    pub fn capacity_for(limit: u64, avg_item_size: u64) -> u64 {
+        match avg_item_size {
+            0 => 0,
+            size => limit / size,
+        }
+    }
+
     pub fn limit(&self) -> u64 {
         self.limit
     }
 
+    /// Change the limit, evicting the oldest values if usage is above the new limit.
+    ///
+    /// History bucket sizes stay as they were computed for the initial limit.
+    pub fn set_limit(&mut self, new_limit: u64) {
+        self.limit = new_limit;
+        if self.usage() > new_limit {
+            self.shrink_to(new_limit);
+        }
+    }
+
+    /// Same as `set_limit`, but rejects a limit of 0 and a limit below the largest
+    /// cached value, leaving the cache unchanged.
+    pub fn set_limit_checked(&mut self, new_limit: u64) -> Result<(), LimitError> {
+        if new_limit == 0 {
+            return Err(LimitError::Zero);
+        }
+
+        let largest = self.items.values().map(|e| e.charged()).max().unwrap_or(0);
+        if new_limit < largest {
+            return Err(LimitError::BelowLargestEntry { largest });
+        }
+
+        self.set_limit(new_limit);
+        Ok(())
+    }
+
+    /// Reject values larger than `bytes` with `StoreResult::TooLarge`.
+    ///
+    /// Rejected values do not evict anything.
+    pub fn set_max_entry_size(&mut self, bytes: u64) {
+        self.max_entry_size = Some(bytes);
+    }
+
+    pub fn max_entry_size(&self) -> Option<u64> {
+        self.max_entry_size
+    }
+
+    /// Evict at most `items` old values to make room for a single value.
+    ///
+    /// Old values are evicted one by one only until the new value fits, instead of
+    /// all at once, so that eviction is spread over many stores.
+    pub fn set_eviction_batch(&mut self, items: usize) {
+        self.eviction_batch = Some(items);
+    }
+
+    pub fn eviction_batch(&self) -> Option<usize> {
+        self.eviction_batch
+    }
+
+    /// `ExpiryMode::Lazy` is used by default.
+    pub fn set_expiry_mode(&mut self, mode: ExpiryMode) {
+        self.expiry_mode = mode;
+    }
+
+    pub fn expiry_mode(&self) -> ExpiryMode {
+        self.expiry_mode
+    }
+
+    /// `AdmissionPolicy::Always` is used by default.
+    ///
+    /// Setting a policy resets the frequencies estimated so far.
+    pub fn set_admission_policy(&mut self, policy: AdmissionPolicy) {
+        self.sketch = match policy {
+            AdmissionPolicy::Always => None,
+            AdmissionPolicy::TinyLfu => Some(FrequencySketch::new(Self::ADMISSION_SKETCH_WIDTH)),
+        };
+    }
+
+    pub fn admission_policy(&self) -> AdmissionPolicy {
+        match self.sketch {
+            Some(_) => AdmissionPolicy::TinyLfu,
+            None => AdmissionPolicy::Always,
+        }
+    }
+
+    /// Check if the key is more frequent than every old key that eviction would remove.
+    fn admits(&self, key: &K) -> bool {
+        let sketch = match self.sketch {
+            Some(ref sketch) => sketch,
+            None => return true,
+        };
+
+        let incoming = sketch.frequency(key);
+        self.history.peek_spillable().into_iter()
+            .filter(|&(victim, _)| victim != key)
+            .all(|(victim, _)| sketch.frequency(victim) < incoming)
+    }
+
+    /// Get number of negative entries that are past their deadline but not removed yet.
+    pub fn expired_count(&self) -> usize {
+        let now = self.clock.now();
+        self.negative.values().filter(|&&deadline| deadline <= now).count()
+    }
+
+    /// Remove expired negative entries if expiry is eager.
+    fn expire(&mut self) {
+        if self.expiry_mode == ExpiryMode::Lazy {
+            return;
+        }
+
+        let now = self.clock.now();
+        let history = &mut self.history;
+        self.negative.retain(|key, deadline| {
+            let fresh = *deadline > now;
+            if !fresh {
+                history.remove(key);
+            }
+            fresh
+        });
+    }
+
+    /// Check if key sizes are counted towards the limit, see `set_count_key_bytes`.
+    pub fn count_key_bytes(&self) -> bool {
+        self.key_bytes.is_some()
+    }
+
+    fn key_size(&self, key: &K) -> u64 {
+        self.key_bytes.map_or(0, |key_bytes| key_bytes(key))
+    }
+
+    /// Call `f` for every read, store, removal and eviction.
+    ///
+    /// Replaces the previous observer. Clones of the cache do not have an observer.
+    pub fn set_observer<F>(&mut self, f: F)
+        where
+            F: for<'a> FnMut(CacheEvent<&'a K>) + Send + 'static
+    {
+        self.observer = Some(Box::new(f));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    /// Load values of missing keys on `get`.
+    ///
+    /// If `get` misses, the loader is called. A value it returns is stored and returned,
+    /// and `None` is a real miss. Other reads do not call the loader.
+    pub fn set_loader<F>(&mut self, f: F)
+        where
+            F: Fn(&K) -> Option<V> + Send + Sync + 'static
+    {
+        self.loader = Some(Arc::new(f));
+    }
+
+    pub fn clear_loader(&mut self) {
+        self.loader = None;
+    }
+
+    /// Use the clock for insertion times and negative entry expiry.
+    ///
+    /// `SystemClock` is used by default.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Arc::new(clock);
+    }
+
     pub fn usage(&self) -> u64 {
         self.history.usage()
     }
 
+    /// Get usage of everything except the old values that eviction would remove next.
+    ///
+    /// This is the memory of the working set, while the rest of `usage` is reclaimable.
+    pub fn working_set_bytes(&self) -> u64 {
+        let old = self.history.simple_usage_iter().next().unwrap_or(0);
+        self.usage().saturating_sub(old)
+    }
+
+    /// Get what the usage would be if the key was removed.
+    ///
+    /// Returns `usage()` if the key is not cached.
+    pub fn usage_without<A: Borrow<K>>(&self, key: A) -> u64 {
+        let size = self.history.usage_of(key.borrow()).unwrap_or(0);
+        self.usage().saturating_sub(size)
+    }
+
     pub fn detailed_usage(&self) -> Vec<(u64, Option<u64>)> {
         self.history.detailed_usage()
     }
 
+    /// Get usage and item count of all history buckets.
+    pub fn detailed_usage_full(&self) -> Vec<BucketUsage> {
+        self.history.detailed_usage_full()
+    }
+
+    /// Get number of cached values.
+    ///
+    /// Negative entries are not counted.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Get read-only access to the eviction history.
+    pub fn history(&self) -> &History<K> {
+        &self.history
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            rotations: self.history.rotation_count(),
+            stored_bytes: self.stored_bytes,
+            evicted_bytes: self.evicted_bytes,
+        }
+    }
+
+    /// Remove all values, negative entries and values buffered in a batch.
     pub fn clear(&mut self) {
+        if let Some(ref mut batch) = self.batch {
+            *batch = Batch::new();
+        }
         self.items.clear();
+        self.negative.clear();
         self.history.clear();
     }
 
+    /// Get keys of values larger than `threshold` bytes, largest first.
+    ///
+    /// Does not refresh the values in history.
+    pub fn entries_over_size(&self, threshold: u64) -> Vec<(&K, u64)> {
+        let mut res = self.items.iter()
+            .map(|(k, e)| (k, e.size()))
+            .filter(|&(_, size)| size > threshold)
+            .collect::<Vec<_>>();
+
+        res.sort_by_key(|&(_, size)| Reverse(size));
+
+        res
+    }
+
+    /// Iterate cached values from the most to the least recently used.
+    ///
+    /// Yields the key, the value and the index of its history bucket, see
+    /// `History::iter_by_recency`. Order within a bucket is arbitrary. Does not refresh
+    /// the values, and negative entries are skipped.
+    pub fn iter_ordered(&self) -> impl Iterator<Item = (&K, &[u8], usize)> + '_ {
+        self.history.iter_by_recency()
+            .filter_map(move |(k, _, i)| self.items.get(k).map(|e| (k, e.value.as_bytes(), i)))
+    }
+
+    /// Visit cached values in arbitrary order until `f` returns `Break`.
+    ///
+    /// Does not refresh the values, and negative entries are skipped.
+    pub fn for_each<F: FnMut(&K, &[u8]) -> ControlFlow<()>>(&self, mut f: F) {
+        for (k, e) in &self.items {
+            if f(k, e.value.as_bytes()).is_break() {
+                return;
+            }
+        }
+    }
+
+    /// Count values by size.
+    ///
+    /// `buckets` are ascending size boundaries. Value of size `s` is counted in the
+    /// first range where `s < boundary`, so the result has one more count than there are
+    /// boundaries: the last one is for values of at least the last boundary.
+    /// Negative entries are not counted.
+    pub fn size_histogram(&self, buckets: &[u64]) -> Vec<usize> {
+        let mut res = vec![0; buckets.len() + 1];
+
+        for e in self.items.values() {
+            let size = e.size();
+            res[buckets.partition_point(|&b| b <= size)] += 1;
+        }
+
+        res
+    }
+
+    /// Age cached items by one step.
+    ///
+    /// Call periodically to make recency decay with wall time, not only with writes.
+    pub fn age(&mut self) {
+        self.history.advance();
+    }
+
     pub fn can_store_bytes(&self, amount: u64) -> bool {
-        self.usage() + amount <= self.limit
+        self.usage().saturating_add(amount) <= self.limit
+    }
+
+    /// Evict items that were already demoted to the old tier, keeping hot items.
+    ///
+    /// Returns the number of evicted items.
+    pub fn clear_old(&mut self) -> usize {
+        self.evict_old(&mut |_, _| ())
+    }
+
+    /// Evict old items, passing evicted values to `on_evict`.
+    fn evict_old<F: FnMut(K, V)>(&mut self, on_evict: &mut F) -> usize {
+        let mut spilled = Vec::new();
+        self.history.spill(&mut spilled);
+        let count = spilled.len();
+
+        for (key, bytes) in spilled {
+            self.evict(key, bytes, on_evict);
+        }
+
+        count
+    }
+
+    /// Remove the key spilled from history.
+    fn evict<F: FnMut(K, V)>(&mut self, key: K, bytes: u64, on_evict: &mut F) {
+        notify(&mut self.observer, CacheEvent::Evict { key: &key, bytes });
+        self.negative.remove(&key);
+        if let Some(e) = self.items.remove(&key) {
+            self.evicted_bytes = self.evicted_bytes.saturating_add(e.charged());
+            on_evict(key, e.value);
+        }
+    }
+
+    /// Merge sparse history buckets, see `History::compact`.
+    pub fn compact(&mut self) {
+        self.history.compact();
+    }
+
+    /// Evict the oldest items until usage is at most `target_usage`.
+    ///
+    /// Items are evicted one by one, see `History::spill_to`, so usage ends just below
+    /// the target. The limit stays unchanged. Returns the number of evicted items.
+    pub fn shrink_to(&mut self, target_usage: u64) -> usize {
+        self.shrink_to_with(target_usage, ShrinkPolicy::EvictOversized)
+    }
+
+    /// Evict the oldest items until `fraction` of the current usage is freed.
+    ///
+    /// Fraction is clamped to `0..=1`. Returns the number of evicted items.
+    pub fn evict_percent(&mut self, fraction: f64) -> usize {
+        let fraction = if fraction > 0.0 { fraction.min(1.0) } else { 0.0 };
+        let usage = self.usage();
+        let freed = (usage as f64 * fraction) as u64;
+        self.shrink_to(usage - freed.min(usage))
+    }
+
+    /// Same as `shrink_to`, but with a choice of what to do with values that are larger
+    /// than `target_usage` on their own.
+    pub fn shrink_to_with(&mut self, target_usage: u64, policy: ShrinkPolicy) -> usize {
+        if policy == ShrinkPolicy::KeepOversized {
+            let oversized = self.items.extract_if(|_, e| e.charged() > target_usage).collect::<Vec<_>>();
+            if !oversized.is_empty() {
+                for (key, _) in &oversized {
+                    self.history.remove(key);
+                }
+
+                let evicted = self.shrink_to(0);
+                for (key, e) in oversized {
+                    self.history.hit_ref(&key, e.charged());
+                    self.items.insert(key, e);
+                }
+                return evicted;
+            }
+        }
+
+        let mut spilled = Vec::new();
+        self.history.spill_to(target_usage, |key, bytes| spilled.push((key, bytes)));
+
+        let evicted = spilled.len();
+        for (key, bytes) in spilled {
+            self.evict(key, bytes, &mut |_, _| ());
+        }
+
+        evicted
     }
 
     fn free_memory(&mut self, required_mem: u64) -> bool {
+        self.free_memory_with(None, required_mem, &mut |_, _| ())
+    }
+
+    /// Free memory for the key to take `size` bytes, counting the bytes it already takes.
+    ///
+    /// The key itself is never evicted, its value is replaced by the caller instead.
+    fn free_memory_for<F: FnMut(K, V)>(&mut self, key: &K, size: u64, on_evict: &mut F) -> bool {
+        let existing = self.history.usage_of(key).unwrap_or(0);
+        self.free_memory_with(Some(key), size.saturating_sub(existing), on_evict)
+    }
+
+    /// Same as `free_memory`, but never evicts `keep` and passes evicted values to `on_evict`.
+    ///
+    /// Nothing is evicted if evicting would not free enough memory.
+    fn free_memory_with<F: FnMut(K, V)>(&mut self, keep: Option<&K>, required_mem: u64, on_evict: &mut F) -> bool {
         if self.can_store_bytes(required_mem) {
             return true;
         }
 
-        let mut spilled = Vec::new();
-        loop {
-            self.history.spill(&mut spilled);
-            for &(ref key, _) in spilled.iter() {
-                self.items.remove(&key);
+        let excess = self.usage().saturating_add(required_mem).saturating_sub(self.limit);
+        let mut victims = Vec::new();
+        let mut freed = 0u64;
+        for (key, bytes) in self.history.peek_spillable() {
+            if keep == Some(key) {
+                continue;
             }
-            spilled.clear();
-
-            if spilled.len() == 0 {
-                if !self.can_store_bytes(required_mem) {
-                    return false;
-                } else {
+            if let Some(batch) = self.eviction_batch {
+                if freed >= excess || victims.len() >= batch {
                     break;
                 }
+                victims.push(key.clone());
             }
+            freed = freed.saturating_add(bytes);
+        }
 
-            if self.can_store_bytes(required_mem) {
-                break;
-            }
+        if freed < excess {
+            return false;
         }
 
-        true
+        match (self.eviction_batch, keep) {
+            (None, None) => {
+                self.evict_old(on_evict);
+            },
+            (None, Some(keep)) => {
+                let mut spilled = Vec::new();
+                self.history.spill_except(keep, &mut spilled);
+                for (key, bytes) in spilled {
+                    self.evict(key, bytes, on_evict);
+                }
+            },
+            (Some(_), _) => {
+                for key in victims {
+                    if let Some(bytes) = self.history.remove_returning_size(&key) {
+                        self.evict(key, bytes, on_evict);
+                    }
+                }
+            },
+        }
+
+        self.can_store_bytes(required_mem)
     }
 
-    pub fn set(&mut self, key: K, value: Vec<u8>) -> StoreResult {
-        let new_required_mem = value.len() as u64;
-        let existing_item_memory_use = match self.items.get(&key) {
-            Some(ref v) => Some(v.len() as u64),
-            None => None,
-        };
+    /// Store the value.
+    ///
+    /// If the same bytes are already stored under the key, the stored value is kept and
+    /// only refreshed in history, so its insertion time and read count do not change.
+    ///
+    /// During a batch, the value is only buffered and `StoreResult::Stored` is returned,
+    /// see `begin_batch`.
+    pub fn set(&mut self, key: K, value: V) -> StoreResult {
+        if let Some(ref mut batch) = self.batch {
+            batch.insert(key, value);
+            return StoreResult::Stored;
+        }
 
-        let real_required_mem = match existing_item_memory_use {
-            Some(existing) => if existing >= new_required_mem { 0 } else { new_required_mem - existing },
-            None => new_required_mem,
-        };
+        self.set_now(key, value)
+    }
 
-        if !self.free_memory(real_required_mem) {
-            if let Some(_) = self.items.remove(&key) {
-                self.history.remove(&key);
+    /// Same as `set`, but stores the value right away even during a batch.
+    fn set_now(&mut self, key: K, value: V) -> StoreResult {
+        self.unbatch(&key);
+        let key_bytes = self.key_size(&key);
+        let unchanged = self.items.get(&key)
+            .filter(|e| e.key_bytes == key_bytes && e.value.as_bytes() == value.as_bytes())
+            .map(|e| e.charged());
+
+        if let Some(charged) = unchanged {
+            self.expire();
+            if let Some(ref mut sketch) = self.sketch {
+                sketch.record(&key);
             }
-            return StoreResult::OutOfMemory;
+            self.history.hit_ref(&key, charged);
+            notify(&mut self.observer, CacheEvent::Set { key: &key, result: StoreResult::Stored });
+            return StoreResult::Stored;
         }
 
-        self.items.insert(key.clone(), value);
-        self.history.hit(key, new_required_mem);
+        self.replace(key, value).0
+    }
 
-        StoreResult::Stored
+    /// Start buffering values stored with `set`.
+    ///
+    /// Until `end_batch`, repeated sets of the same key only replace the buffered value,
+    /// so a key set many times is stored and hit in history once. Buffered values are
+    /// not visible to reads. Other stores and removals of a key discard its buffered value,
+    /// including `set_and_get`, `get_or_insert` and values loaded by `get`, which are stored
+    /// right away. Does nothing if a batch is already started.
+    pub fn begin_batch(&mut self) {
+        if self.batch.is_none() {
+            self.batch = Some(Batch::new());
+        }
     }
 
-    /// Get cached value.
-    pub fn get<A: Borrow<K>>(&mut self, key: A) -> Option<&[u8]> {
-        let res = self.items.get(key.borrow());
+    /// Store values buffered since `begin_batch`, in the order their keys were first set.
+    ///
+    /// Returns keys and results of values that were not stored.
+    pub fn end_batch(&mut self) -> Vec<(K, StoreResult)> {
+        let batch = match self.batch.take() {
+            Some(batch) => batch,
+            None => return Vec::new(),
+        };
 
-        if let Some(ref res) = res {
-            self.history.hit(key.borrow().clone(), res.len() as u64);
+        let mut failed = Vec::new();
+        for (key, value) in batch.values {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            match self.set(key.clone(), value) {
+                StoreResult::Stored => (),
+                result => failed.push((key, result)),
+            }
         }
 
-        res.map(|v| v.borrow())
+        failed
     }
-}
 
-impl<K: Clone> Cache<K> for MemCache<K> {
-    fn fetch<R: Read>(&self, key: K) -> Result<R, CreateReaderError> {
-        Err(CreateReaderError::NotFound)
+    /// Check if `set` buffers values, see `begin_batch`.
+    pub fn in_batch(&self) -> bool {
+        self.batch.is_some()
     }
 
-    fn store<W: Write>(&self, key: K, required_mem: u64) -> Result<W, CreateWriterError> {
-        Err(CreateWriterError::OutOfMemory)
+    /// Discard the buffered value of the key.
+    fn unbatch(&mut self, key: &K) {
+        if let Some(ref mut batch) = self.batch {
+            batch.remove(key);
+        }
     }
-}
-
-#[cfg(test)]
-mod test {
-    use StoreResult;
-    use super::*;
 
-    #[test]
-    fn store_and_get() {
-        let mut cache = MemCache::with_capacity(1000);
-        cache.set("test", vec![2, 3, 4]);
-        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+    /// Store the value and return the value it replaced, if any.
+    ///
+    /// The returned value is no longer cached. If the value can not be stored,
+    /// the existing value is kept and `None` is returned.
+    pub fn replace(&mut self, key: K, value: V) -> (StoreResult, Option<V>) {
+        self.replace_with(key, value, &mut |_, _| ())
     }
 
-    #[test]
-    fn should_not_get_not_stored() {
-        let mut cache = MemCache::<u8>::with_capacity(1000);
-        assert_eq!(None, cache.get(1));
+    /// Store the value and get the stored bytes.
+    ///
+    /// Returns `None` if the value was not stored. History is refreshed only once, by the store.
+    /// The value is stored right away even during a batch.
+    pub fn set_and_get(&mut self, key: K, value: V) -> Option<&[u8]> {
+        match self.set_now(key.clone(), value) {
+            StoreResult::Stored => self.peek(key),
+            _ => None,
+        }
     }
 
-    #[test]
-    fn should_not_store_not_fitting() {
-        let mut cache = MemCache::with_capacity(2);
-        cache.set("test", vec![2, 3, 4]);
-        assert_eq!(None, cache.get("test"));
-    }
+    /// Store the value and report everything that changed.
+    ///
+    /// Besides the result, returns the replaced value and all values evicted to make room.
+    /// The key itself is never evicted to make room, its old value is always the replaced one.
+    pub fn set_detailed(&mut self, key: K, value: V) -> SetOutcome<K, V> {
+        let mut evicted = Vec::new();
+        let (result, replaced) = self.replace_with(key, value, &mut |k, v| evicted.push((k, v)));
 
-    #[test]
-    fn should_store_exactly_fitting() {
-        let mut cache = MemCache::with_capacity(3);
-        cache.set("test", vec![2, 3, 4]);
-        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+        SetOutcome {
+            result,
+            replaced,
+            evicted,
+        }
     }
 
-    #[test]
-    fn prefer_not_storing_new_value_if_it_is_quite_big() {
-        let mut cache = MemCache::with_capacity(3);
-        assert_eq!(StoreResult::Stored, cache.set("test", vec![2, 3]));
-        assert_eq!(StoreResult::OutOfMemory, cache.set("test2", vec![3, 4, 5]));
-        assert_eq!(&[2, 3], cache.get("test").unwrap());
-        assert_eq!(None, cache.get("test2"));
+    fn replace_with<F: FnMut(K, V)>(&mut self, key: K, value: V, on_evict: &mut F) -> (StoreResult, Option<V>) {
+        let observed = self.observer.as_ref().map(|_| key.clone());
+        let res = self.store_with(key, value, on_evict);
+        if let Some(key) = observed {
+            notify(&mut self.observer, CacheEvent::Set { key: &key, result: res.0 });
+        }
+        res
     }
 
-    #[test]
-    fn should_keep_old_if_new_does_not_fit() {
-        let mut cache = MemCache::with_capacity(2);
+    fn store_with<F: FnMut(K, V)>(&mut self, key: K, value: V, on_evict: &mut F) -> (StoreResult, Option<V>) {
+        self.unbatch(&key);
+        self.expire();
+        if let Some(ref mut sketch) = self.sketch {
+            sketch.record(&key);
+        }
+
+        let value_bytes = value.required_bytes();
+        if let Some(max) = self.max_entry_size {
+            if value_bytes > max {
+                return (StoreResult::TooLarge, None);
+            }
+        }
+        let key_bytes = self.key_size(&key);
+        let new_required_mem = value_bytes.saturating_add(key_bytes);
+        if new_required_mem > self.limit {
+            return (StoreResult::ExceedsLimit, None);
+        }
+
+        // Items are looked up once. If nothing has to be evicted, the value is stored
+        // through the same entry, and the key is cloned only if history does not track it.
+        let now = self.clock.now();
+        let key = match self.items.entry(key) {
+            hash_map::Entry::Occupied(mut occupied) => {
+                let real_required_mem = new_required_mem.saturating_sub(occupied.get().charged());
+                if self.history.usage().saturating_add(real_required_mem) <= self.limit {
+                    self.history.hit_ref(occupied.key(), new_required_mem);
+                    let old = mem::replace(occupied.get_mut(), Entry::new(value, key_bytes, now));
+                    self.stored_bytes = self.stored_bytes.saturating_add(new_required_mem);
+                    return (StoreResult::Stored, Some(old.value));
+                }
+                occupied.key().clone()
+            },
+            hash_map::Entry::Vacant(vacant) => {
+                let existing = match self.negative.get(vacant.key()) {
+                    Some(_) => Some(NEGATIVE_ENTRY_BYTES),
+                    None => self.history.usage_of(vacant.key()),
+                };
+                let real_required_mem = new_required_mem.saturating_sub(existing.unwrap_or(0));
+                if self.history.usage().saturating_add(real_required_mem) <= self.limit {
+                    self.negative.remove(vacant.key());
+                    self.history.hit_ref(vacant.key(), new_required_mem);
+                    vacant.insert(Entry::new(value, key_bytes, now));
+                    self.stored_bytes = self.stored_bytes.saturating_add(new_required_mem);
+                    return (StoreResult::Stored, None);
+                }
+                vacant.into_key()
+            },
+        };
+
+        if !self.admits(&key) {
+            return (StoreResult::Rejected, None);
+        }
+        if !self.free_memory_for(&key, new_required_mem, on_evict) {
+            return (StoreResult::OutOfMemory, None);
+        }
+
+        self.negative.remove(&key);
+        let old = self.items.insert(key.clone(), Entry::new(value, key_bytes, now));
+        self.history.hit(key, new_required_mem);
+        self.stored_bytes = self.stored_bytes.saturating_add(new_required_mem);
+
+        (StoreResult::Stored, old.map(|e| e.value))
+    }
+
+    /// Remove the cached value or negative entry, returning the value.
+    pub fn remove<A: Borrow<K>>(&mut self, key: A) -> Option<V> {
+        let key = key.borrow();
+        self.unbatch(key);
+        let negative = self.negative.remove(key).is_some();
+        self.history.remove(key);
+        let value = self.items.remove(key).map(|e| e.value);
+
+        notify(&mut self.observer, CacheEvent::Remove { key, existed: negative || value.is_some() });
+        value
+    }
+
+    /// Remove all cached values matching the predicate and return them.
+    ///
+    /// Extracted keys are removed from history, so usage no longer counts them.
+    /// Negative entries are not passed to the predicate and are kept.
+    pub fn extract_if<F: FnMut(&K, &[u8]) -> bool>(&mut self, mut pred: F) -> Vec<(K, V)> {
+        let extracted = self.items.extract_if(|k, e| pred(k, e.value.as_bytes()))
+            .map(|(k, e)| (k, e.value))
+            .collect::<Vec<_>>();
+
+        for (key, _) in &extracted {
+            self.history.remove(key);
+        }
+
+        extracted
+    }
+
+    /// Remember that the key has no value for the `ttl` duration.
+    ///
+    /// Replaces any cached value. The negative entry is accounted as `NEGATIVE_ENTRY_BYTES`
+    /// and is evicted like any other entry.
+    pub fn set_negative(&mut self, key: K, ttl: Duration) -> StoreResult {
+        self.unbatch(&key);
+        if NEGATIVE_ENTRY_BYTES > self.limit {
+            return StoreResult::ExceedsLimit;
+        }
+
+        self.expire();
+
+        if self.items.remove(&key).is_some() {
+            self.history.remove(&key);
+        }
+
+        let real_required_mem = if self.negative.contains_key(&key) { 0 } else { NEGATIVE_ENTRY_BYTES };
+        if !self.free_memory(real_required_mem) {
+            return StoreResult::OutOfMemory;
+        }
+
+        self.negative.insert(key.clone(), self.clock.now() + ttl);
+        self.history.hit(key, NEGATIVE_ENTRY_BYTES);
+
+        StoreResult::Stored
+    }
+
+    /// Look up the key, distinguishing cached values, remembered misses and unknown keys.
+    ///
+    /// Refreshes the value in history on a hit. Expired negative entries are removed.
+    pub fn lookup<A: Borrow<K>>(&mut self, key: A) -> Lookup<'_> {
+        let key = key.borrow();
+
+        let deadline = match self.negative.get(key) {
+            Some(deadline) => *deadline,
+            None => return match self.get(key) {
+                Some(v) => Lookup::Hit(v),
+                None => Lookup::Miss,
+            },
+        };
+
+        if self.clock.now() < deadline {
+            return Lookup::NegativeHit;
+        }
+
+        self.negative.remove(key);
+        self.history.remove(key);
+
+        Lookup::Miss
+    }
+
+    /// Mark the value as hot by moving it to the most recent bucket.
+    ///
+    /// This is the explicit "mark hot" operation, separate from reads. Returns `false`
+    /// if the key is not cached.
+    pub fn promote<A: Borrow<K>>(&mut self, key: A) -> bool {
+        let len = match self.items.get(key.borrow()) {
+            Some(e) => e.charged(),
+            None => return false,
+        };
+
+        self.history.hit_ref(key.borrow(), len);
+
+        true
+    }
+
+    /// Mark keys as hot before their values are available.
+    ///
+    /// Keys are tracked in history with the hinted sizes, so they count towards usage
+    /// and age like stored values, and a later `set` of the key reuses the reserved space.
+    /// A `get` of a warmed key that was not stored is still a miss. Cached keys are
+    /// promoted with their stored size, and hints that do not fit are skipped.
+    pub fn warm<I: IntoIterator<Item = (K, u64)>>(&mut self, hints: I) {
+        for (key, size) in hints {
+            if self.promote(&key) || self.negative.contains_key(&key) {
+                continue;
+            }
+
+            if self.free_memory_for(&key, size, &mut |_, _| ()) {
+                self.history.hit(key, size);
+            }
+        }
+    }
+
+    /// Get cached value and refresh it in history.
+    ///
+    /// Needs `&mut self`, see `try_get` for reads through a shared reference.
+    /// If the value is not cached and a loader is set, the loaded value is stored and
+    /// returned. `None` is returned if the loader does not have it or it can not be stored.
+    pub fn get<A: Borrow<K>>(&mut self, key: A) -> Option<&[u8]> {
+        let key = key.borrow();
+        if self.loader.is_none() || self.items.contains_key(key) {
+            return self.get_with_metadata(key).map(|(v, _)| v);
+        }
+
+        // Counts the miss.
+        self.read(key);
+
+        let loaded = self.loader.as_ref().and_then(|loader| loader(key))?;
+        self.set_and_get(key.clone(), loaded)
+    }
+
+    /// Get cached value only if it satisfies the predicate.
+    ///
+    /// The value is refreshed in history only if `pred` returns `true`. Otherwise `None` is
+    /// returned, the same as for a value that is not cached.
+    pub fn get_if<A: Borrow<K>, F: FnOnce(&[u8]) -> bool>(&mut self, key: A, pred: F) -> Option<&[u8]> {
+        let key = key.borrow();
+        if !pred(self.items.get(key)?.value.as_bytes()) {
+            return None;
+        }
+        self.get(key)
+    }
+
+    /// Get copies of two cached values.
+    ///
+    /// Both values are refreshed in history. Values are copied, so that they can be used
+    /// together without conflicting borrows.
+    pub fn get2<A: Borrow<K>>(&mut self, k1: A, k2: A) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        let first = self.get(k1).map(|v| v.to_vec());
+        let second = self.get(k2).map(|v| v.to_vec());
+        (first, second)
+    }
+
+    /// Get cached value, or an empty slice if it is not cached.
+    ///
+    /// History is refreshed only on hit.
+    pub fn get_or_default<A: Borrow<K>>(&mut self, key: A) -> &[u8] {
+        self.get(key).unwrap_or(&[])
+    }
+
+    /// Get cached value, or store `default` and get it if the key is not cached.
+    ///
+    /// Returns `None` if the key was not cached and `default` could not be stored.
+    pub fn get_or_insert(&mut self, key: K, default: V) -> Option<&[u8]> {
+        if self.items.contains_key(&key) {
+            return self.get(key);
+        }
+        self.set_and_get(key, default)
+    }
+
+    /// Get a guard for changing the cached value in place.
+    ///
+    /// Counts as a read. When the guard is dropped, a changed value is accounted with its
+    /// new size and reported like a `set`. If it grew past the limit, the oldest other
+    /// values are evicted, and if it grew past the maximum entry size, or past the limit
+    /// on its own, it is removed.
+    pub fn get_mut<A: Borrow<K>>(&mut self, key: A) -> Option<ValueGuard<'_, K, V>> {
+        let key = key.borrow();
+        let charged = self.read(key)?.charged();
+
+        Some(ValueGuard {
+            key: key.clone(),
+            charged,
+            cache: self,
+        })
+    }
+
+    /// Get cached value through a shared reference.
+    ///
+    /// Unlike `get`, this does not refresh the value in history, count the read or call
+    /// the loader, so a value read only with `try_get` is evicted as if it was never
+    /// read. Same as `peek`.
+    pub fn try_get<A: Borrow<K>>(&self, key: A) -> Option<&[u8]> {
+        self.peek(key)
+    }
+
+    /// Get cached value without refreshing it in history.
+    ///
+    /// Use it for scans over many keys, so that a one-time pass does not push the real
+    /// working set out of the cache.
+    pub fn peek<A: Borrow<K>>(&self, key: A) -> Option<&[u8]> {
+        self.items.get(key.borrow()).map(|e| e.value.as_bytes())
+    }
+
+    /// Get cached value together with its size, insertion time and read count.
+    ///
+    /// Counts as a read, so the returned `hits` include this call.
+    pub fn get_with_metadata<A: Borrow<K>>(&mut self, key: A) -> Option<(&[u8], EntryMeta)> {
+        self.read(key.borrow()).map(|e| (e.value.as_bytes(), e.meta()))
+    }
+
+    /// Read the entry, counting the hit and refreshing it in history.
+    fn read(&mut self, key: &K) -> Option<&Entry<V>> {
+        if let Some(ref mut sketch) = self.sketch {
+            sketch.record(key);
+        }
+
+        let entry = self.items.get_mut(key);
+        notify(&mut self.observer, CacheEvent::Get { key, hit: entry.is_some() });
+        let entry = entry?;
+
+        entry.hits += 1;
+        self.history.hit_ref(key, entry.charged());
+
+        Some(entry)
+    }
+
+    /// Move all cached values into another cache.
+    ///
+    /// Each value is removed from this cache only after the target confirms it is stored,
+    /// so on error the value that was rejected and those not flushed yet stay cached. Negative entries are not
+    /// flushed. Returns the number of flushed values.
+    pub fn flush_to<C: Cache<K>>(&mut self, target: &mut C) -> io::Result<usize> {
+        let keys: Vec<K> = self.items.keys().cloned().collect();
+        let mut flushed = 0;
+
+        for key in keys {
+            target.store_bytes(key.clone(), self.items[&key].value.as_bytes())?;
+
+            self.items.remove(&key);
+            self.history.remove(&key);
+            flushed += 1;
+        }
+
+        Ok(flushed)
+    }
+}
+
+impl<K, V> MemCache<K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue + From<Vec<u8>>
+{
+    /// Collect bytes into a value and store it.
+    ///
+    /// The size hint of the iterator is used to reserve the buffer and to reject values
+    /// that can never fit before collecting them. Collection also stops as soon as the
+    /// value is known to be too large.
+    pub fn set_from_bytes_iter<I: IntoIterator<Item = u8>>(&mut self, key: K, bytes: I) -> StoreResult {
+        let bytes = bytes.into_iter();
+        let max_len = match self.max_entry_size {
+            Some(max) => cmp::min(max, self.limit),
+            None => self.limit,
+        };
+        let too_large = |len: u64| match self.max_entry_size {
+            Some(max) if len > max => StoreResult::TooLarge,
+            _ => StoreResult::ExceedsLimit,
+        };
+
+        let (lower, upper) = bytes.size_hint();
+        if lower as u64 > max_len {
+            return too_large(lower as u64);
+        }
+
+        let take_len = if max_len >= usize::MAX as u64 { usize::MAX } else { max_len as usize + 1 };
+        let mut buffer = Vec::with_capacity(cmp::min(upper.unwrap_or(lower), take_len));
+        buffer.extend(bytes.take(take_len));
+        if buffer.len() as u64 > max_len {
+            return too_large(buffer.len() as u64);
+        }
+
+        self.set(key, V::from(buffer))
+    }
+}
+
+impl<K, V> MemCache<K, V>
+    where
+        K: Clone + Eq + Hash + KeyBytes,
+        V: CacheValue
+{
+    /// Count key sizes towards the limit, so that long keys can not make the cache
+    /// use much more memory than the limit.
+    ///
+    /// Values keep the charge they were stored with, so enable this before storing.
+    /// Negative entries are always accounted as `NEGATIVE_ENTRY_BYTES`. Maximum entry
+    /// size still applies to the value only.
+    pub fn set_count_key_bytes(&mut self, count: bool) {
+        self.key_bytes = if count { Some(|key: &K| key.key_bytes()) } else { None };
+    }
+}
+
+/// Indexing reads the value like `peek`, so it does not refresh it in history.
+///
+/// Panics if the key is not cached.
+impl<K, V> ops::Index<&K> for MemCache<K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue
+{
+    type Output = [u8];
+
+    fn index(&self, key: &K) -> &[u8] {
+        self.peek(key).expect("key is not cached")
+    }
+}
+
+impl<K> MemCache<K, Arc<Vec<u8>>>
+    where
+        K: Clone + Eq + Hash
+{
+    /// Get a shared reference to cached value without copying the bytes.
+    pub fn get_ref_counted<A: Borrow<K>>(&mut self, key: A) -> Option<Arc<Vec<u8>>> {
+        self.read(key.borrow()).map(|e| e.value.clone())
+    }
+}
+
+impl<K, V> Cache<K> for MemCache<K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue + From<Vec<u8>>
+{
+    type Reader<'a> = ReadGuard<'a> where K: 'a, V: 'a;
+    type Writer<'a> = MemWriter<'a, K, V> where K: 'a, V: 'a;
+
+    /// Fetch a reader over the cached bytes without copying them.
+    ///
+    /// Because this takes `&self`, it does not refresh the value in history.
+    fn fetch(&self, key: K) -> Result<ReadGuard<'_>, CreateReaderError> {
+        match self.items.get(&key) {
+            Some(e) => Ok(ReadGuard::new(e.value.as_bytes())),
+            None => Err(CreateReaderError::NotFound),
+        }
+    }
+
+    /// Get a writer that stores the written bytes on `flush`.
+    ///
+    /// Fails if the value can not fit, the same way `set` would fail: the entry size limit,
+    /// key bytes and admission are checked and memory is freed for `required_mem` bytes.
+    fn store(&mut self, key: K, required_mem: u64) -> Result<MemWriter<'_, K, V>, CreateWriterError> {
+        let too_large = self.max_entry_size.is_some_and(|max| required_mem > max);
+        let required = required_mem.saturating_add(self.key_size(&key));
+        if too_large || required > self.limit {
+            return Err(CreateWriterError::OutOfMemory);
+        }
+
+        let existing = self.history.usage_of(&key).unwrap_or(0);
+        let fits = self.can_store_bytes(required.saturating_sub(existing));
+        if !fits && (!self.admits(&key) || !self.free_memory_for(&key, required, &mut |_, _| ())) {
+            return Err(CreateWriterError::OutOfMemory);
+        }
+
+        Ok(MemWriter {
+            cache: self,
+            key: Some(key),
+            required_mem,
+            buffer: Vec::with_capacity(required_mem as usize),
+        })
+    }
+}
+
+/// Writer that stores the written bytes to `MemCache` on `flush`.
+///
+/// `flush` fails if the cache does not store the value. Writing more than `required_mem`
+/// bytes fails and discards the value. A writer dropped without `flush` stores nothing, so
+/// values of failed or abandoned writes are never seen by readers.
+pub struct MemWriter<'a, K: 'a + Clone + Eq + Hash, V: 'a + CacheValue + From<Vec<u8>>> {
+    cache: &'a mut MemCache<K, V>,
+    key: Option<K>,
+    required_mem: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'a, K, V> Write for MemWriter<'a, K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue + From<Vec<u8>>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.key.is_none() {
+            return Err(io::Error::other("value is already stored"));
+        }
+        if (self.buffer.len() + buf.len()) as u64 > self.required_mem {
+            self.key = None;
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "value is larger than required_mem"));
+        }
+
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let buffer = mem::take(&mut self.buffer);
+        match self.cache.replace(key, V::from(buffer)).0 {
+            StoreResult::Stored => Ok(()),
+            res => Err(io::Error::other(format!("value not stored: {:?}", res))),
+        }
+    }
+}
+
+/// Mutable access to a cached value that updates accounting when dropped.
+///
+/// Returned by `MemCache::get_mut`.
+pub struct ValueGuard<'a, K: 'a + Clone + Eq + Hash, V: 'a + CacheValue> {
+    cache: &'a mut MemCache<K, V>,
+    key: K,
+    /// Bytes charged for the value before it was changed.
+    charged: u64,
+}
+
+impl<'a, K, V> ops::Deref for ValueGuard<'a, K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.cache.items[&self.key].value
+    }
+}
+
+impl<'a, K, V> ops::DerefMut for ValueGuard<'a, K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue
+{
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.cache.items.get_mut(&self.key).expect("guarded value is cached").value
+    }
+}
+
+impl<'a, K, V> Drop for ValueGuard<'a, K, V>
+    where
+        K: Clone + Eq + Hash,
+        V: CacheValue
+{
+    fn drop(&mut self) {
+        let cache = &mut *self.cache;
+        let (size, charged) = match cache.items.get(&self.key) {
+            Some(e) => (e.size(), e.charged()),
+            None => return,
+        };
+        if charged == self.charged {
+            return;
+        }
+
+        let result = if cache.max_entry_size.is_some_and(|max| size > max) {
+            StoreResult::TooLarge
+        } else if charged > cache.limit {
+            StoreResult::ExceedsLimit
+        } else {
+            StoreResult::Stored
+        };
+        if result != StoreResult::Stored {
+            notify(&mut cache.observer, CacheEvent::Set { key: &self.key, result });
+            cache.remove(&self.key);
+            return;
+        }
+
+        cache.history.hit_ref(&self.key, charged);
+        cache.stored_bytes = cache.stored_bytes.saturating_add(charged);
+
+        // Only other values are evicted, oldest first, without aging history.
+        if cache.usage() > cache.limit {
+            let mut spilled = Vec::new();
+            cache.history.spill_to_except(cache.limit, &self.key, |key, bytes| spilled.push((key, bytes)));
+            for (key, bytes) in spilled {
+                cache.evict(key, bytes, &mut |_, _| ());
+            }
+        }
+
+        notify(&mut cache.observer, CacheEvent::Set { key: &self.key, result });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ Read, Write };
+    use StoreResult;
+    use Cache;
+    use CreateReaderError;
+    use CreateWriterError;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn store_and_get() {
+        let mut cache = MemCache::with_capacity(1000);
+        cache.set("test", vec![2, 3, 4]);
+        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+    }
+
+    #[test]
+    fn should_not_get_not_stored() {
+        let mut cache = MemCache::<u8>::with_capacity(1000);
+        assert_eq!(None, cache.get(1));
+    }
+
+    #[test]
+    fn should_not_store_not_fitting() {
+        let mut cache = MemCache::with_capacity(2);
+        cache.set("test", vec![2, 3, 4]);
+        assert_eq!(None, cache.get("test"));
+    }
+
+    #[test]
+    fn should_store_exactly_fitting() {
+        let mut cache = MemCache::with_capacity(3);
+        cache.set("test", vec![2, 3, 4]);
+        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+    }
+
+    #[test]
+    fn prefer_not_storing_new_value_if_it_is_quite_big() {
+        let mut cache = MemCache::with_capacity(3);
+        assert_eq!(StoreResult::Stored, cache.set("test", vec![2, 3]));
+        assert_eq!(StoreResult::OutOfMemory, cache.set("test2", vec![3, 4, 5]));
+        assert_eq!(&[2, 3], cache.get("test").unwrap());
+        assert_eq!(None, cache.get("test2"));
+    }
+
+    #[test]
+    fn should_keep_old_if_new_does_not_fit() {
+        let mut cache = MemCache::with_capacity(2);
         cache.set("test", vec![2, 3]);
         cache.set("test2", vec![3, 4, 5]);
         assert_eq!(None, cache.get("test2"));
         assert_eq!(&[2, 3], cache.get("test").unwrap());
     }
+
+    #[test]
+    fn aged_items_are_evicted() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+        cache.age();
+        cache.age();
+        cache.age();
+        assert_eq!(StoreResult::Stored, cache.set("test2", vec![0; 9]));
+        assert_eq!(None, cache.get("test"));
+    }
+
+    #[test]
+    fn promoted_item_survives_eviction() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+        cache.set("test2", vec![4, 5]);
+        cache.age();
+        cache.age();
+        assert!(cache.promote("test"));
+        cache.age();
+        assert_eq!(StoreResult::Stored, cache.set("test3", vec![0; 8]));
+        assert_eq!(&[2, 3], cache.get("test").unwrap());
+        assert_eq!(None, cache.get("test2"));
+    }
+
+    #[test]
+    fn should_not_promote_not_stored() {
+        let mut cache = MemCache::<u8>::with_capacity(10);
+        assert!(!cache.promote(1));
+    }
+
+    #[test]
+    fn fetch_reads_stored() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3, 4]);
+
+        let mut res = Vec::new();
+        cache.fetch("test").unwrap().read_to_end(&mut res).unwrap();
+        assert_eq!(vec![2, 3, 4], res);
+    }
+
+    #[test]
+    fn store_writes_value() {
+        let mut cache = MemCache::<&str>::with_capacity(10);
+        cache.store_bytes("test", &[2, 3, 4]).unwrap();
+        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+    }
+
+    #[test]
+    fn store_fails_if_not_fitting() {
+        let mut cache = MemCache::<&str>::with_capacity(2);
+        match cache.store("test", 3) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        };
+    }
+
+    #[test]
+    fn store_fails_past_required_mem() {
+        let mut cache = MemCache::<&str>::with_capacity(10);
+        {
+            let mut writer = cache.store("big", 2).unwrap();
+            assert!(writer.write_all(&[0; 11]).is_err());
+            assert!(writer.write_all(&[0; 1]).is_err());
+        }
+        assert_eq!(None, cache.peek("big"));
+
+        match cache.store_bytes("big", &[0; 11]) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        }
+    }
+
+    #[test]
+    fn unflushed_writer_stores_nothing() {
+        let mut cache = MemCache::<&str>::with_capacity(10);
+        cache.set("test", vec![1, 2, 3]);
+
+        cache.store("test", 3).unwrap().write_all(&[4, 5]).unwrap();
+        cache.store("new", 3).unwrap().write_all(&[6, 7, 8]).unwrap();
+
+        assert_eq!(Some(&[1u8, 2, 3][..]), cache.peek("test"));
+        assert_eq!(None, cache.peek("new"));
+    }
+
+    #[test]
+    fn store_checks_key_bytes_and_admission() {
+        let mut cache = MemCache::<&str>::with_capacity(10);
+        cache.set_count_key_bytes(true);
+        match cache.store("test", 7) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        }
+
+        let (mut cache, _) = scan_after_hot_key(AdmissionPolicy::TinyLfu);
+        match cache.store("other", 2) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        }
+        assert!(cache.peek("hot").is_some());
+    }
+
+    #[test]
+    fn fetch_does_not_copy() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3, 4]);
+
+        let fetched = cache.fetch("test").unwrap().as_slice().as_ptr() as usize;
+        let stored = cache.get("test").unwrap().as_ptr() as usize;
+        assert_eq!(stored, fetched);
+    }
+
+    #[test]
+    fn should_not_fetch_not_stored() {
+        let cache = MemCache::<u8>::with_capacity(10);
+        match cache.fetch(1) {
+            Err(CreateReaderError::NotFound) => (),
+            _ => panic!("expected not found"),
+        }
+    }
+
+    #[test]
+    fn get_with_metadata_counts_hits() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3, 4]);
+
+        let (value, first) = cache.get_with_metadata("test").unwrap();
+        assert_eq!(&[2, 3, 4], value);
+        assert_eq!(3, first.size);
+        assert_eq!(1, first.hits);
+
+        cache.get("test");
+        let (_, second) = cache.get_with_metadata("test").unwrap();
+        assert_eq!(3, second.hits);
+        assert_eq!(first.inserted_at, second.inserted_at);
+    }
+
+    #[test]
+    fn get_with_metadata_resets_on_set() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3, 4]);
+        cache.get("test");
+        cache.set("test", vec![5]);
+
+        let (value, meta) = cache.get_with_metadata("test").unwrap();
+        assert_eq!(&[5], value);
+        assert_eq!(1, meta.size);
+        assert_eq!(1, meta.hits);
+    }
+
+    #[test]
+    fn lookup_distinguishes_states() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("test", vec![2, 3]);
+        cache.set_negative("absent", Duration::from_secs(3600));
+
+        assert_eq!(Lookup::Hit(&[2, 3]), cache.lookup("test"));
+        assert_eq!(Lookup::NegativeHit, cache.lookup("absent"));
+        assert_eq!(Lookup::Miss, cache.lookup("unknown"));
+        assert_eq!(None, cache.get("absent"));
+    }
+
+    #[test]
+    fn negative_entry_expires() {
+        let mut cache = MemCache::<&str>::with_capacity(100);
+        cache.set_negative("absent", Duration::from_secs(0));
+        assert_eq!(NEGATIVE_ENTRY_BYTES, cache.usage());
+
+        assert_eq!(Lookup::Miss, cache.lookup("absent"));
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn set_replaces_negative_entry() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set_negative("test", Duration::from_secs(3600));
+        cache.set("test", vec![2, 3]);
+
+        assert_eq!(Lookup::Hit(&[2, 3]), cache.lookup("test"));
+        assert_eq!(2, cache.usage());
+    }
+
+    #[test]
+    fn set_negative_replaces_value() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("test", vec![2, 3]);
+        cache.set_negative("test", Duration::from_secs(3600));
+
+        assert_eq!(Lookup::NegativeHit, cache.lookup("test"));
+        assert_eq!(NEGATIVE_ENTRY_BYTES, cache.usage());
+    }
+
+    #[test]
+    fn clear_old_keeps_newest() {
+        let mut cache = MemCache::with_capacity(100);
+        for i in 0..10u8 {
+            cache.set(i, vec![i; 10]);
+        }
+        assert_eq!(100, cache.usage());
+
+        assert_eq!(4, cache.clear_old());
+        assert_eq!(60, cache.usage());
+        assert_eq!(None, cache.get(0));
+        assert_eq!(None, cache.get(3));
+        assert_eq!(&[9; 10], cache.get(9).unwrap());
+        assert_eq!(&[4; 10], cache.get(4).unwrap());
+    }
+
+    #[test]
+    fn capacity_for_divides_limit() {
+        assert_eq!(10, MemCache::<u8>::capacity_for(100, 10));
+        assert_eq!(3, MemCache::<u8>::capacity_for(100, 30));
+        assert_eq!(0, MemCache::<u8>::capacity_for(10, 30));
+        assert_eq!(0, MemCache::<u8>::capacity_for(100, 0));
+    }
+
+    #[test]
+    fn get_ref_counted_shares_value() {
+        let mut cache = ArcMemCache::with_capacity(10);
+        cache.set("test", Arc::new(vec![2, 3, 4]));
+        assert_eq!(3, cache.usage());
+
+        let first = cache.get_ref_counted("test").unwrap();
+        let second = cache.get_ref_counted("test").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+    }
+
+    #[test]
+    fn should_reject_value_over_max_entry_size() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set_max_entry_size(3);
+        cache.set("test", vec![2, 3]);
+        cache.age();
+        cache.age();
+        cache.age();
+
+        assert_eq!(StoreResult::TooLarge, cache.set("test2", vec![0; 4]));
+        assert_eq!(StoreResult::TooLarge, cache.set("test", vec![0; 4]));
+        assert_eq!(&[2, 3], cache.get("test").unwrap());
+        assert_eq!(None, cache.get("test2"));
+        assert_eq!(2, cache.usage());
+    }
+
+    #[test]
+    fn should_store_value_at_max_entry_size() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set_max_entry_size(3);
+        assert_eq!(StoreResult::Stored, cache.set("test", vec![2, 3, 4]));
+    }
+
+    #[test]
+    fn entries_over_size_are_largest_first() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("small", vec![0; 2]);
+        cache.set("large", vec![0; 10]);
+        cache.set("medium", vec![0; 5]);
+        cache.set("exact", vec![0; 3]);
+
+        assert_eq!(vec![(&"large", 10), (&"medium", 5)], cache.entries_over_size(3));
+        assert!(cache.entries_over_size(10).is_empty());
+    }
+
+    #[test]
+    fn peek_scan_keeps_hot_items() {
+        let mut cache = MemCache::with_capacity(100);
+        for key in &["cold0", "cold1", "cold2", "cold3", "cold4", "cold5", "hot0", "hot1"] {
+            cache.set(*key, vec![0; 10]);
+        }
+
+        for key in &["cold0", "cold1", "cold2", "cold3", "cold4", "cold5"] {
+            assert_eq!(Some(&[0; 10][..]), cache.peek(*key));
+        }
+
+        assert_eq!(StoreResult::Stored, cache.set("new", vec![0; 30]));
+        assert!(cache.peek("hot0").is_some());
+        assert!(cache.peek("hot1").is_some());
+        assert_eq!(None, cache.peek("cold0"));
+    }
+
+    #[test]
+    fn get_scan_evicts_hot_items() {
+        let mut cache = MemCache::with_capacity(100);
+        for key in &["cold0", "cold1", "cold2", "cold3", "cold4", "cold5", "hot0", "hot1"] {
+            cache.set(*key, vec![0; 10]);
+        }
+
+        for key in &["cold0", "cold1", "cold2", "cold3", "cold4", "cold5"] {
+            cache.get(*key);
+        }
+
+        assert_eq!(StoreResult::Stored, cache.set("new", vec![0; 30]));
+        assert_eq!(None, cache.peek("hot0"));
+    }
+
+    #[test]
+    fn clone_is_independent() {
+        let mut cache = MemCache::with_capacity(30);
+        cache.set("test", vec![0; 10]);
+        cache.set("test2", vec![0; 10]);
+
+        let mut clone = cache.clone();
+        assert_eq!(cache.usage(), clone.usage());
+        assert_eq!(cache.limit(), clone.limit());
+
+        clone.age();
+        clone.age();
+        clone.age();
+        assert_eq!(StoreResult::Stored, clone.set("test3", vec![0; 30]));
+        assert_eq!(None, clone.peek("test"));
+
+        assert_eq!(20, cache.usage());
+        assert_eq!(&[0; 10], cache.get("test").unwrap());
+        assert_eq!(&[0; 10], cache.get("test2").unwrap());
+        assert_eq!(None, cache.peek("test3"));
+    }
+
+    #[test]
+    fn stats_count_rotations() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![0; 2]);
+        assert_eq!(0, cache.stats().rotations);
+
+        cache.set("test2", vec![0; 2]);
+        cache.age();
+        assert_eq!(2, cache.stats().rotations);
+    }
+
+    #[test]
+    fn shrink_to_evicts_oldest() {
+        let mut cache = MemCache::with_capacity(100);
+        for i in 0..10u8 {
+            cache.set(i, vec![i; 10]);
+        }
+
+        assert_eq!(5, cache.shrink_to(50));
+        assert_eq!(50, cache.usage());
+        assert_eq!(100, cache.limit());
+        assert!((0..4).all(|i| cache.peek(i).is_none()));
+        assert_eq!(1, (4..6).filter(|&i| cache.peek(i).is_some()).count());
+        assert!(cache.peek(6).is_some());
+        assert!(cache.peek(9).is_some());
+    }
+
+    #[test]
+    fn shrink_to_zero_evicts_everything() {
+        let mut cache = MemCache::with_capacity(100);
+        for i in 0..10u8 {
+            cache.set(i, vec![i; 10]);
+        }
+
+        assert_eq!(10, cache.shrink_to(0));
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn replace_returns_old_value() {
+        let mut cache = MemCache::with_capacity(10);
+        assert_eq!((StoreResult::Stored, None), cache.replace("test", vec![2, 3]));
+        assert_eq!((StoreResult::Stored, Some(vec![2, 3])), cache.replace("test", vec![4]));
+        assert_eq!(&[4], cache.get("test").unwrap());
+        assert_eq!(1, cache.usage());
+    }
+
+    #[test]
+    fn history_tracks_all_items() {
+        let mut cache = MemCache::with_capacity(100);
+        assert!(cache.is_empty());
+        for i in 0..15u8 {
+            cache.set(i, vec![i; 10]);
+        }
+        cache.set(3, vec![3; 5]);
+
+        assert_eq!(8, cache.len());
+        assert_eq!(cache.len(), cache.history().total_items());
+    }
+
+    #[test]
+    fn failed_replace_keeps_old_value() {
+        let mut cache = MemCache::with_capacity(3);
+        cache.set("test", vec![2, 3]);
+        assert_eq!((StoreResult::ExceedsLimit, None), cache.replace("test", vec![0; 4]));
+        assert_eq!(&[2, 3], cache.get("test").unwrap());
+    }
+
+    #[test]
+    fn failed_overwrite_does_not_remove_existing() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+        cache.set("test2", vec![4; 5]);
+
+        assert_eq!(StoreResult::OutOfMemory, cache.set("test", vec![0; 9]));
+
+        assert_eq!(&[2, 3], cache.get("test").unwrap());
+        assert_eq!(&[4; 5], cache.get("test2").unwrap());
+        assert_eq!(7, cache.usage());
+        assert_eq!(2, cache.history().total_items());
+    }
+
+    #[test]
+    fn get_or_default_returns_value_or_empty() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+        assert_eq!(&[2, 3], cache.get_or_default("test"));
+        assert_eq!(&[] as &[u8], cache.get_or_default("missing"));
+        assert!(!cache.history().contains(&"missing"));
+    }
+
+    #[test]
+    fn flush_to_file_cache() {
+        use std::env;
+        use std::fs;
+        use std::process;
+        use file::FileCache;
+
+        let dir = env::temp_dir().join(format!("bytecache-{}-flush_to_file_cache", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut files = FileCache::new(dir.clone(), 1000);
+
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("first", vec![2, 3]);
+        cache.set("second", vec![4, 5, 6]);
+        cache.set_negative("missing", Duration::from_secs(60));
+
+        assert_eq!(2, cache.flush_to(&mut files).unwrap());
+        assert_eq!(0, cache.len());
+        assert_eq!(NEGATIVE_ENTRY_BYTES, cache.usage());
+
+        let mut res = Vec::new();
+        files.fetch("first").unwrap().read_to_end(&mut res).unwrap();
+        assert_eq!(vec![2, 3], res);
+        res.clear();
+        files.fetch("second").unwrap().read_to_end(&mut res).unwrap();
+        assert_eq!(vec![4, 5, 6], res);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn eviction_batch_limits_evicted_items() {
+        let fill = |cache: &mut MemCache<u8>| {
+            for i in 0..10u8 {
+                cache.set(i, vec![i; 10]);
+            }
+            cache.age();
+            cache.age();
+            cache.age();
+        };
+
+        let mut cache = MemCache::with_capacity(100);
+        fill(&mut cache);
+        assert_eq!(StoreResult::Stored, cache.set(10, vec![0; 10]));
+        assert_eq!(1, cache.len());
+
+        let mut cache = MemCache::with_capacity(100);
+        cache.set_eviction_batch(2);
+        fill(&mut cache);
+        assert_eq!(StoreResult::Stored, cache.set(10, vec![0; 10]));
+        assert_eq!(10, cache.len());
+        assert_eq!(StoreResult::Stored, cache.set(11, vec![0; 15]));
+        assert_eq!(9, cache.len());
+        assert_eq!(StoreResult::OutOfMemory, cache.set(12, vec![0; 35]));
+        assert_eq!(9, cache.len());
+        assert_eq!(cache.len(), cache.history().total_items());
+    }
+
+    #[test]
+    fn negative_entry_expires_with_manual_clock() {
+        use clock::ManualClock;
+
+        let clock = ManualClock::new();
+        let mut cache = MemCache::<&str>::with_capacity(100);
+        cache.set_clock(clock.clone());
+        cache.set_negative("absent", Duration::from_secs(60));
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(Lookup::NegativeHit, cache.lookup("absent"));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Lookup::Miss, cache.lookup("absent"));
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn insertion_time_is_read_from_clock() {
+        use clock::{ Clock, ManualClock };
+
+        let clock = ManualClock::new();
+        let mut cache = MemCache::with_capacity(100);
+        cache.set_clock(clock.clone());
+        clock.advance(Duration::from_secs(10));
+        cache.set("test", vec![2, 3]);
+
+        assert_eq!(clock.now(), cache.get_with_metadata("test").unwrap().1.inserted_at);
+    }
+
+    #[test]
+    fn get2_returns_both_values() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("a", vec![1, 2]);
+        cache.set("b", vec![3]);
+
+        assert_eq!((Some(vec![1, 2]), Some(vec![3])), cache.get2("a", "b"));
+        assert_eq!((Some(vec![3]), None), cache.get2("b", "missing"));
+        assert_eq!(3, cache.get_with_metadata("b").unwrap().1.hits);
+    }
+
+    #[test]
+    fn store_and_get_boxed_slice() {
+        let mut cache = MemCache::<&str, Box<[u8]>>::with_capacity(10);
+        let value: Box<[u8]> = vec![2, 3, 4].into_boxed_slice();
+        let ptr = value.as_ptr();
+
+        assert_eq!(StoreResult::Stored, cache.set("test", value));
+        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+        assert_eq!(ptr, cache.peek("test").unwrap().as_ptr());
+        assert_eq!(3, cache.usage());
+
+        cache.store_bytes("other", &[5, 6]).unwrap();
+        assert_eq!(&[5, 6], cache.get("other").unwrap());
+    }
+
+    #[test]
+    fn debug_prints_limit_and_item_count() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("a", vec![0; 10]);
+        cache.set("b", vec![0; 5]);
+        assert_eq!("MemCache { limit: 100, usage: 15, items: 2, negative: 0 }", format!("{:?}", cache));
+    }
+
+    #[test]
+    fn usage_without_subtracts_key_size() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("a", vec![0; 10]);
+        cache.set("b", vec![0; 5]);
+        cache.set_negative("c", Duration::from_secs(60));
+
+        assert_eq!(5 + NEGATIVE_ENTRY_BYTES, cache.usage_without("a"));
+        assert_eq!(15, cache.usage_without("c"));
+        assert_eq!(cache.usage(), cache.usage_without("missing"));
+        assert_eq!(2, cache.len());
+    }
+
+    #[test]
+    fn new_uses_default_bucket_constants() {
+        let mut cache = MemCache::<u8>::new(100);
+        for i in 0..10u8 {
+            cache.set(i, vec![i; 10]);
+        }
+
+        let usage = cache.detailed_usage();
+        assert_eq!(MemCache::<u8>::DEFAULT_BUCKET_COUNT as usize + 2, usage.len());
+        assert_eq!(Some(100 / MemCache::<u8>::DEFAULT_BUCKET_DIVISOR), usage[1].1);
+    }
+
+    #[test]
+    fn get_if_refreshes_only_when_predicate_holds() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+
+        assert_eq!(None, cache.get_if("test", |v| v.len() > 2));
+        assert_eq!(1, cache.get_with_metadata("test").unwrap().1.hits);
+
+        assert_eq!(Some(&[2, 3][..]), cache.get_if("test", |v| v[0] == 2));
+        assert_eq!(3, cache.get_with_metadata("test").unwrap().1.hits);
+
+        assert_eq!(None, cache.get_if("missing", |_| true));
+    }
+
+    #[test]
+    fn get_if_rejected_value_is_not_refreshed() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+        cache.age();
+        cache.get_if("test", |_| false);
+        cache.age();
+        cache.age();
+
+        assert_eq!(StoreResult::Stored, cache.set("test2", vec![0; 9]));
+        assert_eq!(None, cache.peek("test"));
+    }
+
+    #[test]
+    fn size_histogram_counts_values_in_ranges() {
+        let mut cache = MemCache::with_capacity(1000);
+        for (i, &size) in [0, 1, 9, 10, 11, 99, 100, 500].iter().enumerate() {
+            cache.set(i, vec![0; size]);
+        }
+        cache.set_negative(100, Duration::from_secs(60));
+
+        assert_eq!(vec![3, 3, 2], cache.size_histogram(&[10, 100]));
+        assert_eq!(vec![8], cache.size_histogram(&[]));
+        assert_eq!(vec![1, 7], cache.size_histogram(&[1]));
+    }
+
+    #[test]
+    fn warmed_key_reserves_space_and_is_evicted_last() {
+        let mut cache = MemCache::with_capacity(100);
+        for i in 0..9u8 {
+            cache.set(i, vec![i; 10]);
+        }
+        cache.warm(vec![(9, 10)]);
+
+        assert_eq!(None, cache.get(9));
+        assert_eq!(100, cache.usage());
+        assert_eq!(9, cache.len());
+
+        assert_eq!(StoreResult::Stored, cache.set(9, vec![9; 10]));
+        assert_eq!(10, cache.len());
+        assert_eq!(100, cache.usage());
+
+        assert_eq!(StoreResult::Stored, cache.set(10, vec![10; 10]));
+        assert_eq!(None, cache.peek(0));
+        assert_eq!(&[9; 10], cache.peek(9).unwrap());
+    }
+
+    #[test]
+    fn warm_skips_hints_that_do_not_fit() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+        cache.warm(vec![("big", 20), ("test", 5)]);
+
+        assert!(!cache.history().contains(&"big"));
+        assert_eq!(Some(2), cache.history().usage_of(&"test"));
+    }
+
+    #[test]
+    fn set_detailed_reports_replaced_and_evicted() {
+        let mut cache = MemCache::with_capacity(100);
+        for i in 0..4u8 {
+            cache.set(i, vec![i; 10]);
+        }
+        cache.age();
+        cache.age();
+        cache.age();
+        for i in 4..10u8 {
+            cache.set(i, vec![i; 10]);
+        }
+        assert_eq!(100, cache.usage());
+
+        let mut outcome = cache.set_detailed(9, vec![0; 20]);
+        outcome.evicted.sort();
+
+        assert_eq!(StoreResult::Stored, outcome.result);
+        assert_eq!(Some(vec![9; 10]), outcome.replaced);
+        assert_eq!((0..4u8).map(|i| (i, vec![i; 10])).collect::<Vec<_>>(), outcome.evicted);
+        assert_eq!(70, cache.usage());
+    }
+
+    #[test]
+    fn lazy_expiry_counts_expired_entries() {
+        use clock::ManualClock;
+
+        let clock = ManualClock::new();
+        let mut cache = MemCache::with_capacity(1000);
+        cache.set_clock(clock.clone());
+        cache.set_negative("a", Duration::from_secs(10));
+        cache.set_negative("b", Duration::from_secs(20));
+        assert_eq!(0, cache.expired_count());
+
+        clock.advance(Duration::from_secs(10));
+        cache.set("c", vec![1]);
+        assert_eq!(1, cache.expired_count());
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(2, cache.expired_count());
+
+        assert_eq!(Lookup::Miss, cache.lookup("a"));
+        assert_eq!(1, cache.expired_count());
+    }
+
+    #[test]
+    fn eager_expiry_removes_expired_entries_on_set() {
+        use clock::ManualClock;
+
+        let clock = ManualClock::new();
+        let mut cache = MemCache::with_capacity(1000);
+        cache.set_clock(clock.clone());
+        cache.set_expiry_mode(ExpiryMode::Eager);
+        cache.set_negative("a", Duration::from_secs(10));
+        cache.set_negative("b", Duration::from_secs(20));
+
+        clock.advance(Duration::from_secs(10));
+        cache.set("c", vec![1]);
+        assert_eq!(0, cache.expired_count());
+        assert_eq!(NEGATIVE_ENTRY_BYTES + 1, cache.usage());
+
+        clock.advance(Duration::from_secs(10));
+        cache.set_negative("d", Duration::from_secs(10));
+        assert_eq!(0, cache.expired_count());
+        assert_eq!(NEGATIVE_ENTRY_BYTES + 1, cache.usage());
+        assert!(!cache.history().contains(&"b"));
+    }
+
+    #[test]
+    fn store_and_fetch_bytes_through_trait() {
+        let mut cache = MemCache::<&str>::with_capacity(10);
+
+        cache.store_bytes("test", &[2, 3, 4]).unwrap();
+        assert_eq!(vec![2, 3, 4], cache.fetch_bytes("test").unwrap());
+
+        match cache.fetch_bytes("missing") {
+            Err(CreateReaderError::NotFound) => (),
+            _ => panic!("expected not found"),
+        }
+        match cache.store_bytes("big", &[0; 11]) {
+            Err(CreateWriterError::OutOfMemory) => (),
+            _ => panic!("expected out of memory"),
+        }
+    }
+
+    #[test]
+    fn overwrite_does_not_clone_key() {
+        use std::cell::Cell;
+
+        thread_local!(static CLONES: Cell<usize> = const { Cell::new(0) });
+
+        #[derive(Debug, Eq, PartialEq, Hash)]
+        struct CountedKey(u32);
+
+        impl Clone for CountedKey {
+            fn clone(&self) -> CountedKey {
+                CLONES.with(|c| c.set(c.get() + 1));
+                CountedKey(self.0)
+            }
+        }
+
+        let mut cache = MemCache::with_capacity(100);
+        cache.set(CountedKey(1), vec![1; 10]);
+        assert_eq!(1, CLONES.with(|c| c.get()));
+
+        assert_eq!((StoreResult::Stored, Some(vec![1; 10])), cache.replace(CountedKey(1), vec![2; 20]));
+        assert_eq!(1, CLONES.with(|c| c.get()));
+        assert_eq!(20, cache.usage());
+        assert_eq!(&[2; 20], cache.peek(CountedKey(1)).unwrap());
+    }
+
+    #[test]
+    fn iter_ordered_goes_from_hot_to_cold() {
+        let mut cache = MemCache::with_capacity(100);
+        for i in 0..8u8 {
+            cache.set(i, vec![i; 10]);
+        }
+        cache.set_negative(8, Duration::from_secs(60));
+        cache.get(0);
+
+        let ordered: Vec<(u8, usize)> = cache.iter_ordered().map(|(&k, v, i)| {
+            assert_eq!(&[k; 10], v);
+            (k, i)
+        }).collect();
+
+        let bucket_of = |key: u8| ordered.iter().find(|&&(k, _)| k == key).unwrap().1;
+        assert_eq!(8, ordered.len());
+        assert!(ordered.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(0, bucket_of(0));
+        assert!(bucket_of(1) > bucket_of(7));
+    }
+
+    #[test]
+    fn try_reserve_small_succeeds() {
+        let mut cache = MemCache::with_capacity(100);
+        assert!(cache.try_reserve(10).is_ok());
+        cache.set(1u8, vec![1]);
+        assert!(cache.try_reserve(10).is_ok());
+        assert_eq!(&[1], cache.get(1).unwrap());
+    }
+
+    #[test]
+    fn try_reserve_too_much_fails_without_abort() {
+        let mut cache = MemCache::<u8>::with_capacity(100);
+        assert!(cache.try_reserve(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn set_from_fitting_bytes_iter() {
+        let mut cache = MemCache::<&str>::with_capacity(20);
+        assert_eq!(StoreResult::Stored, cache.set_from_bytes_iter("test", 2..5));
+        assert_eq!(&[2, 3, 4], cache.get("test").unwrap());
+        assert_eq!(StoreResult::Stored, cache.set_from_bytes_iter("filtered", (0..20).filter(|b| b % 2 == 0)));
+        assert_eq!(13, cache.usage());
+    }
+
+    #[test]
+    fn set_from_non_fitting_bytes_iter() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("test", vec![2, 3]);
+
+        assert_eq!(StoreResult::ExceedsLimit, cache.set_from_bytes_iter("big", 0..11));
+        assert_eq!(StoreResult::ExceedsLimit, cache.set_from_bytes_iter("unknown", (0..).filter(|b| b % 2 == 0)));
+
+        cache.set_max_entry_size(3);
+        assert_eq!(StoreResult::TooLarge, cache.set_from_bytes_iter("big", 0..4));
+        assert_eq!(StoreResult::TooLarge, cache.set_from_bytes_iter("unknown", (0..100).filter(|_| true)));
+        assert_eq!(&[2, 3], cache.get("test").unwrap());
+    }
+
+    #[test]
+    fn store_failure_reports_reason() {
+        let mut cache = MemCache::with_capacity(10);
+        cache.set("hot", vec![0; 8]);
+
+        assert_eq!(StoreResult::ExceedsLimit, cache.set("huge", vec![0; 11]));
+        assert_eq!(StoreResult::OutOfMemory, cache.set("big", vec![0; 5]));
+        cache.set_max_entry_size(4);
+        assert_eq!(StoreResult::TooLarge, cache.set("huge", vec![0; 11]));
+        assert_eq!(8, cache.usage());
+
+        let mut tiny = MemCache::<&str>::with_capacity(NEGATIVE_ENTRY_BYTES - 1);
+        assert_eq!(StoreResult::ExceedsLimit, tiny.set_negative("absent", Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn set_and_get_returns_stored_value() {
+        let mut cache = MemCache::with_capacity(10);
+        assert_eq!(Some(&[2, 3][..]), cache.set_and_get("test", vec![2, 3]));
+        assert_eq!(None, cache.set_and_get("big", vec![0; 11]));
+        assert_eq!(1, cache.get_with_metadata("test").unwrap().1.hits);
+    }
+
+    #[test]
+    fn remove_returns_value() {
+        let mut cache = MemCache::with_capacity(100);
+        cache.set("a", vec![1, 2]);
+        cache.set_negative("b", Duration::from_secs(60));
+
+        assert_eq!(Some(vec![1, 2]), cache.remove("a"));
+        assert_eq!(None, cache.remove("a"));
+        assert_eq!(None, cache.remove("b"));
+        assert_eq!(Lookup::Miss, cache.lookup("b"));
+        assert_eq!(0, cache.usage());
+        assert_eq!(0, cache.history().total_items());
+    }
+
+    fn assert_invariants(cache: &MemCache<u8>) {
+        let stored: u64 = cache.items.values().map(|e| e.charged()).sum();
+        let negative = cache.negative.len() as u64 * NEGATIVE_ENTRY_BYTES;
+        assert_eq!(stored + negative, cache.usage());
+        assert!(cache.usage() <= cache.limit());
+        assert_eq!(cache.len() + cache.negative.len(), cache.history().total_items());
+
+        for (key, e) in &cache.items {
+            assert_eq!(Some(e.charged()), cache.history().usage_of(key));
+        }
+        for key in cache.negative.keys() {
+            assert_eq!(Some(NEGATIVE_ENTRY_BYTES), cache.history().usage_of(key));
+        }
+    }
+
+    #[test]
+    fn random_operations_keep_invariants() {
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for &limit in &[20, 100, 1000] {
+            let mut cache = MemCache::with_capacity(limit);
+            if limit == 100 {
+                cache.set_eviction_batch(3);
+            }
+            if limit == 1000 {
+                cache.set_count_key_bytes(true);
+            }
+
+            for _ in 0..5000 {
+                let key = (next() % 32) as u8;
+                let len = (next() % (limit / 2)) as usize;
+                match next() % 100 {
+                    0..=39 => { cache.set(key, vec![key; len]); },
+                    40..=64 => { cache.get(key); },
+                    65..=74 => { cache.remove(key); },
+                    75..=79 => { cache.set_negative(key, Duration::from_secs(60)); },
+                    80..=84 => { cache.set_detailed(key, vec![key; len]); },
+                    85..=89 => cache.age(),
+                    90..=93 => { cache.shrink_to(limit / 2); },
+                    94..=96 => cache.compact(),
+                    97 => { cache.clear_old(); },
+                    98 => { cache.promote(key); },
+                    _ => cache.clear(),
+                }
+                assert_invariants(&cache);
+            }
+        }
+    }
+
+    #[test]
+    fn get_or_insert_returns_existing_value() {
+        let mut cache = MemCache::new(10);
+        cache.set("a", vec![1, 2]);
+
+        assert_eq!(Some(&[1, 2][..]), cache.get_or_insert("a", vec![3]));
+        assert_eq!(Some(&[1, 2][..]), cache.peek("a"));
+        assert_eq!(2, cache.usage());
+    }
+
+    #[test]
+    fn get_or_insert_stores_default_on_miss() {
+        let mut cache = MemCache::new(10);
+
+        assert_eq!(Some(&[3, 4][..]), cache.get_or_insert("a", vec![3, 4]));
+        assert_eq!(Some(&[3, 4][..]), cache.peek("a"));
+        assert_eq!(2, cache.usage());
+    }
+
+    #[test]
+    fn get_or_insert_returns_none_when_default_does_not_fit() {
+        let mut cache = MemCache::new(3);
+
+        assert_eq!(None, cache.get_or_insert("a", vec![0; 4]));
+        assert_eq!(None, cache.peek("a"));
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn with_limit_fraction_is_proportional() {
+        let half = MemCache::<&str>::with_limit_fraction(0.5);
+        let quarter = MemCache::<&str>::with_limit_fraction(0.25);
+
+        assert!(quarter.limit() > 0);
+        assert_eq!(half.limit() / 2, quarter.limit());
+        assert_eq!(0, MemCache::<&str>::with_limit_fraction(-1.0).limit());
+    }
+
+    #[cfg(feature = "sysinfo")]
+    #[test]
+    fn with_limit_fraction_reads_system_memory() {
+        let total = total_system_memory().unwrap();
+        assert!(total > 0);
+        assert_eq!(total, MemCache::<&str>::with_limit_fraction(1.0).limit());
+    }
+
+    #[test]
+    fn counts_key_bytes_when_enabled() {
+        let key = "https://example.com/a/long/key";
+        let mut cache = MemCache::new(100);
+        cache.set_count_key_bytes(true);
+        assert!(cache.count_key_bytes());
+
+        assert_eq!(StoreResult::Stored, cache.set(key, vec![1, 2]));
+        assert_eq!(key.len() as u64 + 2, cache.usage());
+
+        cache.get(key);
+        assert_eq!(key.len() as u64 + 2, cache.usage());
+
+        let mut tight = MemCache::new(key.len() as u64 + 1);
+        tight.set_count_key_bytes(true);
+        assert_eq!(StoreResult::ExceedsLimit, tight.set(key, vec![1, 2]));
+    }
+
+    #[test]
+    fn does_not_count_key_bytes_by_default() {
+        let mut cache = MemCache::new(100);
+        assert!(!cache.count_key_bytes());
+
+        cache.set("long key", vec![1, 2]);
+        assert_eq!(2, cache.usage());
+    }
+
+    fn scan_after_hot_key(policy: AdmissionPolicy) -> (MemCache<&'static str>, StoreResult) {
+        let mut cache = MemCache::new(10);
+        cache.set_admission_policy(policy);
+
+        cache.set("hot", vec![0; 2]);
+        for _ in 0..5 {
+            cache.get("hot");
+        }
+        for &key in &["a", "b", "c", "d"] {
+            cache.set(key, vec![0; 2]);
+        }
+
+        let result = cache.set("scan", vec![0; 2]);
+        (cache, result)
+    }
+
+    #[test]
+    fn tiny_lfu_rejects_one_time_key() {
+        let (mut cache, result) = scan_after_hot_key(AdmissionPolicy::TinyLfu);
+
+        assert_eq!(AdmissionPolicy::TinyLfu, cache.admission_policy());
+        assert_eq!(StoreResult::Rejected, result);
+        assert_eq!(None, cache.get("scan"));
+        assert!(cache.get("hot").is_some());
+    }
+
+    #[test]
+    fn always_admits_one_time_key() {
+        let (mut cache, result) = scan_after_hot_key(AdmissionPolicy::Always);
+
+        assert_eq!(StoreResult::Stored, result);
+        assert!(cache.get("scan").is_some());
+        assert_eq!(None, cache.get("hot"));
+    }
+
+    #[test]
+    fn tiny_lfu_admits_frequent_key() {
+        let mut cache = MemCache::new(10);
+        cache.set_admission_policy(AdmissionPolicy::TinyLfu);
+        for &key in &["a", "b", "c", "d", "e"] {
+            cache.set(key, vec![0; 2]);
+        }
+
+        for _ in 0..3 {
+            cache.get("new");
+        }
+        assert_eq!(StoreResult::Stored, cache.set("new", vec![0; 2]));
+    }
+
+    #[test]
+    fn identical_set_keeps_stored_value() {
+        let mut cache = MemCache::new(10);
+        cache.set("a", vec![1, 2, 3]);
+        cache.get("a");
+        let stored = cache.peek("a").unwrap().as_ptr();
+
+        assert_eq!(StoreResult::Stored, cache.set("a", vec![1, 2, 3]));
+        assert_eq!(stored, cache.peek("a").unwrap().as_ptr());
+        assert_eq!(2, cache.get_with_metadata("a").unwrap().1.hits);
+        assert_eq!(3, cache.usage());
+
+        assert_eq!(StoreResult::Stored, cache.set("a", vec![4, 5, 6]));
+        assert_eq!(&[4, 5, 6], cache.peek("a").unwrap());
+        assert_eq!(1, cache.get_with_metadata("a").unwrap().1.hits);
+    }
+
+    #[test]
+    fn extract_if_takes_matching_values() {
+        let mut cache = MemCache::new(100);
+        cache.set("small", vec![1]);
+        cache.set("large", vec![2; 10]);
+        cache.set("larger", vec![3; 20]);
+        cache.set_negative("missing", Duration::from_secs(60));
+
+        let mut extracted = cache.extract_if(|_, v| v.len() > 5);
+        extracted.sort();
+
+        assert_eq!(vec![("large", vec![2; 10]), ("larger", vec![3; 20])], extracted);
+        assert_eq!(1 + NEGATIVE_ENTRY_BYTES, cache.usage());
+        assert_eq!(1, cache.len());
+        assert_eq!(Some(&[1][..]), cache.peek("small"));
+        assert_eq!(Lookup::NegativeHit, cache.lookup("missing"));
+        assert!(!cache.history().contains(&"large"));
+    }
+
+    fn cache_with_oversized_entry() -> MemCache<&'static str> {
+        let mut cache = MemCache::new(100);
+        cache.set("a", vec![0; 5]);
+        cache.set("b", vec![0; 5]);
+        cache.set("large", vec![0; 30]);
+        cache
+    }
+
+    #[test]
+    fn shrink_evicts_oversized_entry() {
+        let mut cache = cache_with_oversized_entry();
+
+        assert_eq!(3, cache.shrink_to_with(20, ShrinkPolicy::EvictOversized));
+        assert_eq!(0, cache.usage());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn shrink_keeps_oversized_entry() {
+        let mut cache = cache_with_oversized_entry();
+
+        assert_eq!(2, cache.shrink_to_with(20, ShrinkPolicy::KeepOversized));
+        assert_eq!(30, cache.usage());
+        assert_eq!(1, cache.len());
+        assert!(cache.peek("large").is_some());
+
+        assert_eq!(0, cache.shrink_to_with(40, ShrinkPolicy::KeepOversized));
+        assert_eq!(30, cache.usage());
+    }
+
+    #[test]
+    fn get_mut_accounts_new_size() {
+        let mut cache = MemCache::new(100);
+        cache.set("a", vec![1, 2]);
+
+        cache.get_mut("a").unwrap().extend_from_slice(&[3, 4, 5]);
+        assert_eq!(&[1, 2, 3, 4, 5], cache.peek("a").unwrap());
+        assert_eq!(5, cache.usage());
+        assert_eq!(Some(5), cache.history().usage_of(&"a"));
+
+        cache.get_mut("a").unwrap().truncate(1);
+        assert_eq!(1, cache.usage());
+        assert!(cache.get_mut("missing").is_none());
+    }
+
+    #[test]
+    fn get_mut_growth_evicts_old_values() {
+        let mut cache = MemCache::new(10);
+        cache.set("old", vec![0; 4]);
+        cache.set("a", vec![0; 4]);
+
+        cache.get_mut("a").unwrap().resize(8, 0);
+        assert!(cache.usage() <= 10);
+        assert_eq!(None, cache.peek("old"));
+        assert_eq!(Some(&[0; 8][..]), cache.peek("a"));
+
+        cache.get_mut("a").unwrap().resize(11, 0);
+        assert_eq!(None, cache.peek("a"));
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn get_mut_growth_keeps_aged_value_and_reports_set() {
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = MemCache::new(10);
+        let observed = events.clone();
+        cache.set_observer(move |event| {
+            if let CacheEvent::Set { key, result } = event {
+                observed.lock().unwrap().push((*key, result));
+            }
+        });
+
+        cache.set("a", vec![0; 4]);
+        cache.set("b", vec![0; 2]);
+        for _ in 0..3 {
+            cache.age();
+        }
+        cache.set("c", vec![0; 2]);
+        let stored = cache.stats().stored_bytes;
+
+        cache.get_mut("a").unwrap().resize(7, 0);
+        assert_eq!(Some(&[0; 7][..]), cache.peek("a"));
+        assert_eq!(None, cache.peek("b"));
+        assert!(cache.usage() <= 10);
+        assert_eq!(stored + 7, cache.stats().stored_bytes);
+        assert_eq!(Some(&("a", StoreResult::Stored)), events.lock().unwrap().last());
+
+        cache.get_mut("a").unwrap().resize(11, 0);
+        assert_eq!(Some(&("a", StoreResult::ExceedsLimit)), events.lock().unwrap().last());
+    }
+
+    #[test]
+    fn batch_coalesces_sets_of_same_key() {
+        let mut cache = MemCache::new(100);
+        cache.begin_batch();
+        assert!(cache.in_batch());
+
+        cache.set("a", vec![1]);
+        cache.set("a", vec![2, 2]);
+        cache.set("a", vec![3, 3, 3]);
+        assert_eq!(None, cache.peek("a"));
+        assert_eq!(0, cache.usage());
+
+        let rotations = cache.stats().rotations;
+        assert!(cache.end_batch().is_empty());
+        assert!(!cache.in_batch());
+
+        assert_eq!(Some(&[3, 3, 3][..]), cache.peek("a"));
+        assert_eq!(3, cache.usage());
+        assert_eq!(1, cache.history().total_items());
+        assert_eq!(rotations, cache.stats().rotations);
+    }
+
+    #[test]
+    fn batch_reports_values_that_were_not_stored() {
+        let mut cache = MemCache::new(4);
+        cache.begin_batch();
+        cache.set("a", vec![0; 10]);
+        cache.set("b", vec![0; 2]);
+        cache.remove("b");
+
+        assert_eq!(vec![("a", StoreResult::ExceedsLimit)], cache.end_batch());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn set_limit_checked_raises_limit() {
+        let mut cache = MemCache::new(10);
+        cache.set("a", vec![0; 10]);
+
+        assert_eq!(Ok(()), cache.set_limit_checked(20));
+        assert_eq!(20, cache.limit());
+        assert_eq!(StoreResult::Stored, cache.set("b", vec![0; 10]));
+        assert_eq!(20, cache.usage());
+    }
+
+    #[test]
+    fn set_limit_checked_lowers_limit_and_evicts() {
+        let mut cache = MemCache::new(20);
+        cache.set("a", vec![0; 8]);
+        cache.set("b", vec![0; 8]);
+
+        assert_eq!(Ok(()), cache.set_limit_checked(10));
+        assert_eq!(10, cache.limit());
+        assert!(cache.usage() <= 10);
+        assert_eq!(None, cache.peek("a"));
+        assert!(cache.peek("b").is_some());
+    }
+
+    #[test]
+    fn set_limit_checked_rejects_invalid_limits() {
+        let mut cache = MemCache::new(20);
+        cache.set("a", vec![0; 8]);
+
+        assert_eq!(Err(LimitError::Zero), cache.set_limit_checked(0));
+        assert_eq!(Err(LimitError::BelowLargestEntry { largest: 8 }), cache.set_limit_checked(7));
+        assert_eq!(20, cache.limit());
+        assert_eq!(8, cache.usage());
+    }
+
+    #[test]
+    fn working_set_excludes_old_values() {
+        let mut cache = MemCache::<u8>::new(10);
+        assert_eq!(0, cache.working_set_bytes());
+
+        cache.set(0, vec![0; 2]);
+        assert_eq!(cache.usage(), cache.working_set_bytes());
+
+        for key in 1..5 {
+            cache.set(key, vec![0; 2]);
+        }
+        let old = cache.history().simple_usage()[0];
+        assert!(old > 0);
+        assert_eq!(10, cache.usage());
+        assert_eq!(cache.usage() - old, cache.working_set_bytes());
+    }
+
+    #[test]
+    fn observer_receives_events() {
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = MemCache::new(10);
+        let sink = events.clone();
+        cache.set_observer(move |e: CacheEvent<&&str>| sink.lock().unwrap().push(match e {
+            CacheEvent::Get { key, hit } => CacheEvent::Get { key: *key, hit },
+            CacheEvent::Set { key, result } => CacheEvent::Set { key: *key, result },
+            CacheEvent::Remove { key, existed } => CacheEvent::Remove { key: *key, existed },
+            CacheEvent::Evict { key, bytes } => CacheEvent::Evict { key: *key, bytes },
+        }));
+
+        cache.set("a", vec![0; 6]);
+        cache.get("a");
+        cache.get("b");
+        for _ in 0..3 {
+            cache.age();
+        }
+        cache.set("c", vec![0; 6]);
+        cache.set("d", vec![0; 20]);
+        cache.remove("c");
+        cache.remove("c");
+
+        assert_eq!(vec![
+            CacheEvent::Set { key: "a", result: StoreResult::Stored },
+            CacheEvent::Get { key: "a", hit: true },
+            CacheEvent::Get { key: "b", hit: false },
+            CacheEvent::Evict { key: "a", bytes: 6 },
+            CacheEvent::Set { key: "c", result: StoreResult::Stored },
+            CacheEvent::Set { key: "d", result: StoreResult::ExceedsLimit },
+            CacheEvent::Remove { key: "c", existed: true },
+            CacheEvent::Remove { key: "c", existed: false },
+        ], *events.lock().unwrap());
+
+        assert!(cache.clone().observer.is_none());
+    }
+
+    #[test]
+    fn loader_fills_misses() {
+        let mut cache = MemCache::new(10);
+        cache.set_loader(|key: &&str| match *key {
+            "known" => Some(vec![1, 2]),
+            "large" => Some(vec![0; 20]),
+            _ => None,
+        });
+
+        assert_eq!(Some(&[1, 2][..]), cache.get("known"));
+        assert_eq!(Some(&[1, 2][..]), cache.peek("known"));
+        assert_eq!(2, cache.usage());
+
+        assert_eq!(None, cache.get("unknown"));
+        assert_eq!(None, cache.get("large"));
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn get_without_loader_does_not_store() {
+        let mut cache = MemCache::<&str>::new(10);
+        assert_eq!(None, cache.get("missing"));
+        assert!(cache.is_empty());
+
+        cache.set_loader(|_| Some(vec![1]));
+        cache.clear_loader();
+        assert_eq!(None, cache.get("missing"));
+    }
+
+    #[test]
+    fn stats_count_stored_and_evicted_bytes() {
+        let mut cache = MemCache::new(100);
+        cache.set("a", vec![0; 4]);
+        cache.age();
+        cache.set("b", vec![0; 6]);
+
+        cache.shrink_to(6);
+        let stats = cache.stats();
+        assert_eq!(10, stats.stored_bytes);
+        assert_eq!(4, stats.evicted_bytes);
+
+        cache.set("b", vec![1; 6]);
+        cache.remove("b");
+        cache.clear();
+        let stats = cache.stats();
+        assert_eq!(16, stats.stored_bytes);
+        assert_eq!(4, stats.evicted_bytes);
+    }
+
+    #[test]
+    fn index_reads_without_refreshing() {
+        let mut cache = MemCache::new(10);
+        cache.set("a", vec![1, 2]);
+
+        assert_eq!(&[1, 2], &cache[&"a"]);
+        assert_eq!(1, cache.get_with_metadata("a").unwrap().1.hits);
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_panics_on_missing_key() {
+        let cache = MemCache::<&str>::new(10);
+        let _ = &cache[&"missing"];
+    }
+
+    #[test]
+    fn for_each_stops_on_break() {
+        let mut cache = MemCache::new(100);
+        for key in 0..10u8 {
+            cache.set(key, vec![key % 2]);
+        }
+
+        let mut visited = 0;
+        let mut found = None;
+        cache.for_each(|&k, v| {
+            visited += 1;
+            if v == [1] {
+                found = Some(k);
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        });
+
+        assert_eq!(1, found.unwrap() % 2);
+        assert!(visited < 10);
+
+        let mut all = 0;
+        cache.for_each(|_, _| {
+            all += 1;
+            ControlFlow::Continue(())
+        });
+        assert_eq!(10, all);
+    }
+
+    #[test]
+    fn try_get_reads_through_shared_reference() {
+        let mut cache = MemCache::new(100);
+        cache.set("a", vec![1, 2]);
+
+        let shared = &cache;
+        assert_eq!(Some(&[1, 2][..]), shared.try_get("a"));
+        assert_eq!(None, shared.try_get("b"));
+    }
+
+    #[test]
+    fn try_get_does_not_protect_from_eviction() {
+        let mut cache = MemCache::new(10);
+        cache.set("a", vec![0; 5]);
+        cache.set("b", vec![0; 5]);
+        for _ in 0..3 {
+            cache.age();
+        }
+
+        assert!(cache.try_get("a").is_some());
+        cache.set("c", vec![0; 5]);
+        assert_eq!(None, cache.try_get("a"));
+        assert!(cache.try_get("c").is_some());
+    }
+
+    #[test]
+    fn evict_percent_frees_oldest_fraction() {
+        let mut cache = MemCache::new(100);
+        for key in 0..10u8 {
+            cache.set(key, vec![key; 10]);
+        }
+
+        assert_eq!(5, cache.evict_percent(0.5));
+        assert_eq!(50, cache.usage());
+        assert!((0..4).all(|key| cache.peek(key).is_none()));
+        assert!((6..10).all(|key| cache.peek(key).is_some()));
+
+        assert_eq!(0, cache.evict_percent(-1.0));
+        assert_eq!(0, cache.evict_percent(f64::NAN));
+        assert_eq!(50, cache.usage());
+
+        assert_eq!(5, cache.evict_percent(2.0));
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn failed_overwrite_keeps_aged_value() {
+        let mut cache = MemCache::<u8>::new(10);
+        cache.set(1, vec![1; 4]);
+        for _ in 0..3 {
+            cache.age();
+        }
+        cache.set(2, vec![2; 5]);
+
+        assert_eq!(StoreResult::OutOfMemory, cache.set(1, vec![9; 9]));
+        assert_eq!(Some(&[1u8; 4][..]), cache.peek(1));
+        assert!(cache.peek(2).is_some());
+        assert_invariants(&cache);
+
+        cache.set_eviction_batch(1);
+        assert_eq!(StoreResult::OutOfMemory, cache.set(1, vec![9; 9]));
+        assert_eq!(Some(&[1u8; 4][..]), cache.peek(1));
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn replace_returns_aged_value_when_making_room() {
+        let mut cache = MemCache::<u8>::new(10);
+        cache.set(1, vec![1; 4]);
+        cache.set(3, vec![3; 2]);
+        for _ in 0..3 {
+            cache.age();
+        }
+        cache.set(2, vec![2; 4]);
+
+        let (result, replaced) = cache.replace(1, vec![9; 6]);
+        assert_eq!(StoreResult::Stored, result);
+        assert_eq!(Some(vec![1; 4]), replaced);
+        assert_eq!(Some(&[9u8; 6][..]), cache.peek(1));
+        assert_eq!(None, cache.peek(3));
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn flush_to_keeps_values_rejected_by_target() {
+        let mut src = MemCache::<&str>::new(100);
+        src.set("aaaaaaaaaa", vec![1; 5]);
+
+        let mut dst = MemCache::new(20);
+        dst.set_count_key_bytes(true);
+        dst.set("h", vec![0; 9]);
+
+        assert!(src.flush_to(&mut dst).is_err());
+        assert_eq!(Some(&[1u8; 5][..]), src.peek("aaaaaaaaaa"));
+        assert_eq!(1, src.len());
+        assert_eq!(None, dst.peek("aaaaaaaaaa"));
+        assert!(dst.peek("h").is_some());
+    }
+
+    #[test]
+    fn read_back_helpers_store_during_batch() {
+        let mut cache = MemCache::<&str>::new(100);
+        cache.set("k", vec![1]);
+        cache.set_loader(|key: &&str| Some(key.as_bytes().to_vec()));
+        cache.begin_batch();
+
+        cache.set("k", vec![4]);
+        assert_eq!(Some(&[2u8][..]), cache.set_and_get("k", vec![2]));
+        assert_eq!(Some(&[3u8][..]), cache.get_or_insert("new", vec![3]));
+        assert_eq!(Some(&b"ld"[..]), cache.get("ld"));
+
+        assert!(cache.end_batch().is_empty());
+        assert_eq!(Some(&[2u8][..]), cache.peek("k"));
+        assert_eq!(Some(&[3u8][..]), cache.peek("new"));
+        assert_eq!(Some(&b"ld"[..]), cache.peek("ld"));
+    }
 }
@@ -1,41 +1,90 @@
-use std::path::{ PathBuf };
+use std::borrow::Cow;
+use std::path::{ Path, PathBuf };
 use std::convert::AsRef;
 
 /// Replaces invalid characters in potential file name with characters that are valid on this OS.
 ///
 /// Warning, so far linux-only.
 pub fn replace_invalid_path_chars(key: &str) -> String {
+    replace_invalid_path_chars_cow(key).into_owned()
+}
+
+/// Same as `replace_invalid_path_chars`, but borrows the key if it has no invalid
+/// characters, which is the common case.
+fn replace_invalid_path_chars_cow(key: &str) -> Cow<'_, str> {
+    if !key.contains('/') {
+        return Cow::Borrowed(key);
+    }
+
+    Cow::Owned(key.replace('/', "_"))
+}
+
+/// Encode the key into a path component that can be decoded back.
+///
+/// ASCII letters, digits, `-` and `_` are kept, all other bytes are percent-encoded as
+/// `%XX`. Unlike `replace_invalid_path_chars`, different keys always produce different
+/// components, and the result is never `.` or `..`.
+pub fn encode_component(key: &str) -> String {
     let mut res = String::with_capacity(key.len());
 
-    for c in key.chars() {
-        res.push(match c {
-            '/' => '_',
-            c => c,
-        });
+    for &b in key.as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => res.push(b as char),
+            b => res.push_str(&format!("%{:02X}", b)),
+        }
     }
 
     res
 }
 
+/// Decode the component produced by `encode_component`.
+///
+/// Returns `None` if the component contains invalid escapes or does not decode
+/// to valid UTF-8.
+pub fn decode_component(component: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(component.len());
+    let mut iter = component.bytes();
+
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let high = (iter.next()? as char).to_digit(16)?;
+            let low = (iter.next()? as char).to_digit(16)?;
+            bytes.push((high * 16 + low) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
 /// Construct a valid path for provided string key.
 ///
 /// `subdirs`: Maximum number of subdirectories to generate for this key.
-/// `subdir_len`: Subdir name length.
+/// `subdir_len`: Subdir name length in chars. No subdirectories are generated if it is 0.
+///
+/// The key is always the last path component, and no component is ever empty.
 pub fn construct(key: &str, subdirs: usize, subdir_len: usize) -> Option<PathBuf> {
-    if let "" = key {
+    let key = &*replace_invalid_path_chars_cow(key);
+    if key.is_empty() {
         return None;
     }
 
-    let key = &replace_invalid_path_chars(key);
-
     let mut path = PathBuf::new();
     let mut dir_offset = 0;
 
+    // Subdirectories are split on char boundaries, so that non-ASCII keys are not cut.
+    let subdirs = if subdir_len == 0 { 0 } else { subdirs };
+    let mut boundaries = key.char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(key.len()))
+        .step_by(subdir_len.max(1))
+        .skip(1);
     for _ in 0..subdirs {
-        let next_offset = dir_offset + subdir_len;
-        if next_offset > key.len() {
-            break;
-        }
+        let next_offset = match boundaries.next() {
+            Some(offset) => offset,
+            None => break,
+        };
         path.push(&key[dir_offset..next_offset]);
         dir_offset = next_offset;
     }
@@ -45,6 +94,108 @@ pub fn construct(key: &str, subdirs: usize, subdir_len: usize) -> Option<PathBuf
     Some(path)
 }
 
+/// Same as `construct`, but generates fewer subdirectories if the path would be longer
+/// than `max_total_len` bytes.
+///
+/// Returns `None` if the key alone is longer than `max_total_len`.
+pub fn construct_bounded(key: &str, subdirs: usize, subdir_len: usize, max_total_len: usize) -> Option<PathBuf> {
+    (0..=subdirs).rev()
+        .filter_map(|subdirs| construct(key, subdirs, subdir_len))
+        .find(|path| path.as_os_str().len() <= max_total_len)
+}
+
+/// Construct a valid path for provided string key on case-insensitive file systems.
+///
+/// Same as `construct`, but the key is lowercased before sharding, and a hash of the
+/// original key is appended to the last component, so that keys that differ only in case
+/// do not map to the same file.
+pub fn construct_case_folded(key: &str, subdirs: usize, subdir_len: usize) -> Option<PathBuf> {
+    if key.is_empty() {
+        return None;
+    }
+
+    let folded = format!("{}-{:08x}", key.to_lowercase(), fnv1a(key.as_bytes()));
+    construct(&folded, subdirs, subdir_len)
+}
+
+/// 32-bit FNV-1a hash.
+///
+/// Used instead of `DefaultHasher`, because paths must not change between Rust versions.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for &b in bytes {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Key that can be turned into a path component.
+///
+/// Implemented for strings, integers and byte slices. There is no blanket implementation
+/// for `Display` types, because it would conflict with the hex-encoding implementation
+/// for `[u8]`.
+pub trait PathKey {
+    /// Get the string used to build a path for this key.
+    fn to_path_component(&self) -> String;
+}
+
+impl<T: PathKey + ?Sized> PathKey for &T {
+    fn to_path_component(&self) -> String {
+        (**self).to_path_component()
+    }
+}
+
+impl PathKey for str {
+    fn to_path_component(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl PathKey for String {
+    fn to_path_component(&self) -> String {
+        self.clone()
+    }
+}
+
+macro_rules! impl_path_key_for_display {
+    ($($t:ty),*) => {
+        $(
+            impl PathKey for $t {
+                fn to_path_component(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    }
+}
+
+impl_path_key_for_display!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, char);
+
+/// Byte keys are hex-encoded.
+impl PathKey for [u8] {
+    fn to_path_component(&self) -> String {
+        let mut res = String::with_capacity(self.len() * 2);
+        for b in self {
+            res.push_str(&format!("{:02x}", b));
+        }
+        res
+    }
+}
+
+impl PathKey for Vec<u8> {
+    fn to_path_component(&self) -> String {
+        self[..].to_path_component()
+    }
+}
+
+/// Construct a valid path for provided key.
+///
+/// Same as `construct`, but for any `PathKey`.
+pub fn construct_key<P: PathKey + ?Sized>(key: &P, subdirs: usize, subdir_len: usize) -> Option<PathBuf> {
+    construct(&key.to_path_component(), subdirs, subdir_len)
+}
+
 /// Construct a valid path for provided string key and default subdir parameters.
 ///
 /// Uses `DEF_SUBDIRS` and `DEF_SUBDIR_LEN`.
@@ -70,34 +221,54 @@ impl PathGen {
         }
     }
 
+    /// Create path generator for any `PathKey`.
+    pub fn for_key<P: PathKey + ?Sized>(key: &P, subdirs: usize, subdir_len: usize) -> PathGen {
+        PathGen {
+            base: construct_key(key, subdirs, subdir_len),
+        }
+    }
+
     /// Get path to binary blob.
     pub fn file_path(&self) -> Option<PathBuf> {
         self.base.clone()
     }
 
     /// Get path to blob meta data.
+    ///
+    /// Same as `sidecar_path("meta")`.
     pub fn meta_path(&self) -> Option<PathBuf> {
-        if let Some(ref buf) = self.base {
-            let mut meta_buf = buf.clone();
-
-            let file_name = match meta_buf.file_name() {
-                Some(n) => Some(n.to_string_lossy().into_owned()),
-                None => None,
-            };
-
-            match file_name {
-                Some(name) => {
-                    let name: String = [name.as_ref(), "meta"].connect(".");
-                    meta_buf.set_file_name(name);
-                },
-                None => return None,
-            };
-
-            return Some(meta_buf);
-        }
+        self.sidecar_path("meta")
+    }
+
+    /// Get path to a sidecar file of the blob, `<file>.<kind>`.
+    ///
+    /// Returns `None` if `kind` is empty or contains a path separator.
+    pub fn sidecar_path(&self, kind: &str) -> Option<PathBuf> {
+        self.base.as_ref().and_then(|buf| sidecar_path_for(buf, kind))
+    }
+
+    /// Get paths to binary blob and its meta data in one call.
+    pub fn paths(&self) -> Option<(PathBuf, PathBuf)> {
+        let file_path = self.base.as_ref()?;
+        let meta_path = meta_path_for(file_path)?;
+
+        Some((file_path.clone(), meta_path))
+    }
+}
+
+pub(crate) fn meta_path_for(file_path: &Path) -> Option<PathBuf> {
+    sidecar_path_for(file_path, "meta")
+}
 
-        None
+pub(crate) fn sidecar_path_for(file_path: &Path, kind: &str) -> Option<PathBuf> {
+    if kind.is_empty() || kind.contains(['/', '\\']) {
+        return None;
     }
+
+    let file_name = file_path.file_name()?.to_string_lossy();
+    let name: String = [file_name.as_ref(), kind].join(".");
+
+    Some(file_path.with_file_name(name))
 }
 
 /// Default number of subdirs to generate.
@@ -175,8 +346,8 @@ mod test {
 
     #[test]
     fn should_replace_invalid_path_chars() {
-        assert_eq!("valid", &replace_invalid_path_chars("valid"));
-        assert_eq!("invalid_file_name", &replace_invalid_path_chars("invalid/file/name"));
+        assert_eq!("valid", replace_invalid_path_chars("valid"));
+        assert_eq!("invalid_file_name", replace_invalid_path_chars("invalid/file/name"));
     }
 
     #[test]
@@ -195,4 +366,201 @@ mod test {
         assert_eq!(PathGen::default("aab").meta_path(), Some(PathBuf::from("aa/aab.meta")));
         assert_eq!(PathGen::default("aabbcc").meta_path(), Some(PathBuf::from("aa/bb/cc/aabbcc.meta")));
     }
+
+    #[test]
+    fn path_gen_should_have_sidecar_paths() {
+        let gen = PathGen::default("aabbcc");
+        assert_eq!(gen.sidecar_path("idx"), Some(PathBuf::from("aa/bb/cc/aabbcc.idx")));
+        assert_eq!(gen.sidecar_path("crc"), Some(PathBuf::from("aa/bb/cc/aabbcc.crc")));
+        assert_eq!(gen.sidecar_path("meta"), gen.meta_path());
+        assert_eq!(gen.sidecar_path(""), None);
+        assert_eq!(gen.sidecar_path("a/b"), None);
+        assert_eq!(PathGen::default("").sidecar_path("idx"), None);
+    }
+
+    #[test]
+    fn bounded_path_has_fewer_subdirs() {
+        assert_eq!(construct_bounded("aabbccdd", 3, 2, 100), Some(PathBuf::from("aa/bb/cc/aabbccdd")));
+        assert_eq!(construct_bounded("aabbccdd", 3, 2, 16), Some(PathBuf::from("aa/bb/aabbccdd")));
+        assert_eq!(construct_bounded("aabbccdd", 3, 2, 11), Some(PathBuf::from("aa/aabbccdd")));
+        assert_eq!(construct_bounded("aabbccdd", 3, 2, 8), Some(PathBuf::from("aabbccdd")));
+        assert_eq!(construct_bounded("aabbccdd", 3, 2, 7), None);
+    }
+
+    #[test]
+    fn path_is_generated_for_integer_key() {
+        assert_eq!(construct_key(&123456u64, DEF_SUBDIRS, DEF_SUBDIR_LEN), Some(PathBuf::from("12/34/56/123456")));
+        assert_eq!(PathGen::for_key(&42u64, DEF_SUBDIRS, DEF_SUBDIR_LEN).file_path(), Some(PathBuf::from("42/42")));
+    }
+
+    #[test]
+    fn path_is_generated_for_byte_key() {
+        let key: &[u8] = &[0xab, 0x01, 0xff];
+        assert_eq!(construct_key(key, DEF_SUBDIRS, DEF_SUBDIR_LEN), Some(PathBuf::from("ab/01/ff/ab01ff")));
+        assert_eq!(PathGen::for_key(key, DEF_SUBDIRS, DEF_SUBDIR_LEN).meta_path(), Some(PathBuf::from("ab/01/ff/ab01ff.meta")));
+    }
+
+    #[test]
+    fn empty_byte_key_has_no_path() {
+        let key: &[u8] = &[];
+        assert_eq!(construct_key(key, DEF_SUBDIRS, DEF_SUBDIR_LEN), None);
+    }
+
+    #[test]
+    fn path_gen_should_have_both_paths() {
+        let gen = PathGen::default("aabbcc");
+        assert_eq!(gen.paths(), Some((gen.file_path().unwrap(), gen.meta_path().unwrap())));
+        assert_eq!(PathGen::default("").paths(), None);
+    }
+
+    fn components(path: PathBuf) -> Vec<String> {
+        path.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn zero_subdir_len_generates_no_subdirs() {
+        assert_eq!(components(construct("aabbccdd", 3, 0).unwrap()), vec!["aabbccdd"]);
+        assert_eq!(components(construct("a", 1, 0).unwrap()), vec!["a"]);
+    }
+
+    #[test]
+    fn key_shorter_than_subdir_len_has_no_subdirs() {
+        assert_eq!(construct("a", 1, 5), Some(PathBuf::from("a")));
+        assert_eq!(construct("abcd", 1, 5), Some(PathBuf::from("abcd")));
+        assert_eq!(construct("a", 3, 2), Some(PathBuf::from("a")));
+    }
+
+    #[test]
+    fn key_one_char_shorter_than_full_subdirs() {
+        assert_eq!(construct("aabbc", 3, 2), Some(PathBuf::from("aa/bb/aabbc")));
+        assert_eq!(construct("aabbccd", 2, 4), Some(PathBuf::from("aabb/aabbccd")));
+    }
+
+    #[test]
+    fn sanitized_key_is_never_empty_component() {
+        assert_eq!(construct("/", 3, 2), Some(PathBuf::from("_")));
+        assert_eq!(components(construct("//", 3, 2).unwrap()), vec!["__", "__"]);
+    }
+
+    #[test]
+    fn no_component_is_empty() {
+        for subdirs in 0..5 {
+            for subdir_len in 0..5 {
+                for key in &["a", "ab", "abc", "a/b/c", "aabbccddee"] {
+                    let path = construct(key, subdirs, subdir_len).unwrap();
+                    let components = components(path);
+                    assert!(components.iter().all(|c| !c.is_empty()));
+                    assert_eq!(&replace_invalid_path_chars(key), components.last().unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn case_folded_keys_do_not_collide() {
+        let upper = construct_case_folded("Foo", DEF_SUBDIRS, DEF_SUBDIR_LEN).unwrap();
+        let lower = construct_case_folded("foo", DEF_SUBDIRS, DEF_SUBDIR_LEN).unwrap();
+
+        assert_eq!(PathBuf::from("fo/o-/0c/foo-0c7e1677"), upper);
+        assert_eq!(PathBuf::from("fo/o-/a9/foo-a9f37ed7"), lower);
+        assert!(upper.to_string_lossy().to_lowercase() != lower.to_string_lossy().to_lowercase());
+    }
+
+    #[test]
+    fn case_folded_path_is_lowercase() {
+        assert_eq!(
+            construct_case_folded("FooBar", DEF_SUBDIRS, DEF_SUBDIR_LEN),
+            Some(PathBuf::from("fo/ob/ar/foobar-a325dbe8"))
+        );
+        assert_eq!(construct_case_folded("", DEF_SUBDIRS, DEF_SUBDIR_LEN), None);
+    }
+
+    #[test]
+    fn non_ascii_key_is_split_on_chars() {
+        assert_eq!(construct("aé", 2, 1), Some(PathBuf::from("a/é/aé")));
+        assert_eq!(construct("日本", 1, 2), Some(PathBuf::from("日本/日本")));
+        assert_eq!(construct("日本語", 3, 2), Some(PathBuf::from("日本/日本語")));
+        assert_eq!(construct("aé", DEF_SUBDIRS, DEF_SUBDIR_LEN), Some(PathBuf::from("aé/aé")));
+
+        let folded = construct_case_folded("Aé", DEF_SUBDIRS, DEF_SUBDIR_LEN).unwrap();
+        assert!(folded.starts_with("aé"));
+        assert!(construct_case_folded("日本", DEF_SUBDIRS, DEF_SUBDIR_LEN).is_some());
+
+        assert_eq!(construct_bounded("日本語", 2, 1, 100), Some(PathBuf::from("日/本/日本語")));
+        assert_eq!(construct_bounded("日本語", 2, 1, 13), Some(PathBuf::from("日/日本語")));
+    }
+
+    fn assert_round_trip(key: &str) {
+        let encoded = encode_component(key);
+        assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'%'));
+        assert_eq!(Some(key.to_string()), decode_component(&encoded), "key {:?}", key);
+    }
+
+    #[test]
+    fn encoded_component_round_trips() {
+        let keys = [
+            "",
+            "plain",
+            "with-dash_and_underscore",
+            "a/b/c",
+            "/",
+            "\\windows\\path",
+            ".",
+            "..",
+            "file.meta",
+            "100%",
+            "%2F",
+            "space and\ttab\nnewline",
+            "\0nul\x7fdel\x1besc",
+            "ünïcödé",
+            "日本語のキー",
+            "emoji 🦀",
+            "CON:*?\"<>|",
+        ];
+
+        for key in keys.iter() {
+            assert_round_trip(key);
+        }
+    }
+
+    #[test]
+    fn encoded_component_round_trips_random_strings() {
+        let alphabet: Vec<char> = "aZ09-_./\\%: \t\n\0\x7fé日🦀".chars().collect();
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+
+        for _ in 0..1000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+
+            let len = (seed % 16) as usize;
+            let key: String = (0..len)
+                .map(|i| alphabet[((seed >> (i * 4)) % alphabet.len() as u64) as usize])
+                .collect();
+            assert_round_trip(&key);
+        }
+    }
+
+    #[test]
+    fn encoded_component_is_never_dot() {
+        assert_eq!("%2E", encode_component("."));
+        assert_eq!("%2E%2E", encode_component(".."));
+        assert_eq!("a%2Fb", encode_component("a/b"));
+    }
+
+    #[test]
+    fn invalid_component_is_not_decoded() {
+        assert_eq!(None, decode_component("%"));
+        assert_eq!(None, decode_component("%2"));
+        assert_eq!(None, decode_component("%zz"));
+        assert_eq!(None, decode_component("%+F"));
+        assert_eq!(None, decode_component("%FF"));
+        assert_eq!(Some("/".to_string()), decode_component("%2f"));
+    }
+
+    #[test]
+    fn replace_invalid_path_chars_borrows_clean_key() {
+        assert!(matches!(replace_invalid_path_chars_cow("clean-key.txt"), Cow::Borrowed("clean-key.txt")));
+        assert!(matches!(replace_invalid_path_chars_cow("a/b"), Cow::Owned(_)));
+    }
 }
@@ -0,0 +1,86 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+
+const DEPTH: usize = 4;
+
+/// Count-min sketch of key frequencies with small saturating counters.
+///
+/// Counters are halved after `10 * width` increments, so that old popularity fades.
+#[derive(Clone, Debug)]
+pub(crate) struct FrequencySketch {
+    counters: Vec<u8>,
+    mask: usize,
+    increments: usize,
+    reset_at: usize,
+}
+
+impl FrequencySketch {
+    /// Create sketch with `width` counters per row, rounded up to a power of two.
+    pub(crate) fn new(width: usize) -> FrequencySketch {
+        let width = width.max(1).next_power_of_two();
+        FrequencySketch {
+            counters: vec![0; width * DEPTH],
+            mask: width - 1,
+            increments: 0,
+            reset_at: width.saturating_mul(10),
+        }
+    }
+
+    /// Estimated number of times the key was recorded.
+    pub(crate) fn frequency<K: Hash + ?Sized>(&self, key: &K) -> u8 {
+        (0..DEPTH)
+            .map(|row| self.counters[self.index(key, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn record<K: Hash + ?Sized>(&mut self, key: &K) {
+        for row in 0..DEPTH {
+            let index = self.index(key, row);
+            self.counters[index] = self.counters[index].saturating_add(1);
+        }
+
+        self.increments += 1;
+        if self.increments >= self.reset_at {
+            for counter in &mut self.counters {
+                *counter /= 2;
+            }
+            self.increments /= 2;
+        }
+    }
+
+    fn index<K: Hash + ?Sized>(&self, key: &K, row: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        row * (self.mask + 1) + (hasher.finish() as usize & self.mask)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_recorded_keys() {
+        let mut sketch = FrequencySketch::new(64);
+        for _ in 0..5 {
+            sketch.record("hot");
+        }
+        sketch.record("cold");
+
+        assert!(sketch.frequency("hot") >= 5);
+        assert!(sketch.frequency("cold") >= 1);
+        assert!(sketch.frequency("hot") > sketch.frequency("cold"));
+    }
+
+    #[test]
+    fn halves_counters_over_time() {
+        let mut sketch = FrequencySketch::new(1);
+        for _ in 0..10 {
+            sketch.record("a");
+        }
+
+        assert_eq!(5, sketch.frequency("a"));
+    }
+}